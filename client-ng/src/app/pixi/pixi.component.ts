@@ -23,6 +23,7 @@ const generateCircleTexture = (renderer, color) => {
 export class PixiComponent implements OnInit, OnDestroy {
 
   public address = '';
+  public room = '';
   public app: Application;
   public messages: Array<String> = new Array();
   constructor(private elementRef: ElementRef, private ngZone: NgZone, private processor: ProcessorService) {}
@@ -30,7 +31,7 @@ export class PixiComponent implements OnInit, OnDestroy {
   // private entities: Map<number, Sprite> = new Map();
   // private rect: Sprite;
   private graphics: Graphics;
-  private texture: any;
+  private textures = new Map<string, Texture>();
   private vel = 10;
   private lookup = new Map<number, Sprite>();
   init() {
@@ -39,7 +40,6 @@ export class PixiComponent implements OnInit, OnDestroy {
         width: 500, 
         height: 400
       });
-      this.texture = generateCircleTexture(this.app.renderer, 0x288b22);
       this.app.ticker.add(delta => this.tick(delta));
     });
     this.elementRef.nativeElement.appendChild(this.app.view);
@@ -52,23 +52,40 @@ export class PixiComponent implements OnInit, OnDestroy {
   
 
   tick(delta: number): void {
+    this.processor.processor.update(this.app.ticker.deltaMS);
     const messages = this.processor.processor.get_pending();
     if (messages.length > 0) {
       console.log(messages)
     }
     messages.forEach((x) => {this.messages.push(x)});
+
+    const changes = this.processor.processor.take_changes();
+    changes.removed.forEach((id: number) => {
+      const sprite = this.lookup.get(id);
+      if (sprite) {
+        this.app.stage.removeChild(sprite);
+        sprite.destroy();
+        this.lookup.delete(id);
+      }
+    });
     
-    this.processor.processor.state().forEach((value, index) => {
+    // Draw remote objects slightly in the past so they move smoothly between snapshots.
+    const renderTime = this.processor.processor.render_time();
+    const state = renderTime === undefined
+      ? this.processor.processor.state()
+      : this.processor.processor.interpolated_state(renderTime);
+    state.forEach((value, index) => {
       const id = value['id'];
       
-      if (value['object_info'].hasOwnProperty('Tree')) {
-        let props = value['object_info'].Tree 
-        const x = props['position'][0];
-        const y = props['position'][1];
+      const transform = value['components']['transform'];
+      const renderable = value['components']['renderable'];
+      if (transform && renderable) {
+        const x = transform['position'][0];
+        const y = transform['position'][1];
         if (!this.lookup.has(id)) {
-          const sprite = new Sprite(this.texture);
-          sprite.scale.x = (10/props['size']);
-          sprite.scale.y = (10/props['size']);
+          const sprite = new Sprite(this.textureFor(renderable['color']));
+          sprite.scale.x = (10/renderable['size']);
+          sprite.scale.y = (10/renderable['size']);
           this.lookup.set(id, sprite);
           this.app.stage.addChild(sprite);
           sprite.interactive = true;
@@ -93,11 +110,37 @@ export class PixiComponent implements OnInit, OnDestroy {
     });
   }
 
+  textureFor(color: string): Texture {
+    if (!this.textures.has(color)) {
+      this.textures.set(color, generateCircleTexture(this.app.renderer, parseInt(color.slice(1), 16)));
+    }
+    return this.textures.get(color);
+  }
+
   connect() {
     console.log("Connecting to ", this.address);
     this.processor.processor.connect(this.address);
   }
 
+  joinRoom() {
+    console.log("Joining room ", this.room);
+    this.processor.processor.connect_to_room(this.address, this.room);
+  }
+
+  // The lobby lives next to the session endpoint the address points at.
+  async quickMatch() {
+    const lobby = this.address.replace(/\/session\/?$/, '');
+    const response = await fetch(`${lobby}/rooms/quick-match`, { method: 'POST' });
+    const room = await response.json();
+    this.room = room['code'];
+    this.joinRoom();
+  }
+
+  playOffline() {
+    console.log("Starting offline game");
+    this.processor.processor.connect_offline();
+  }
+
   disconnect() {
     this.processor.processor.disconnect();
   }
@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+
+// Timestamps exchanged by `Message::Sync` are milliseconds on a `Clock`.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    epoch: instant::Instant,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            epoch: instant::Instant::now(),
+        }
+    }
+
+    // Milliseconds since the clock was created.
+    pub fn now(&self) -> f64 {
+        self.epoch.elapsed().as_secs_f64() * 1000.0
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// How many exchanges are considered when picking the best offset.
+const SAMPLE_WINDOW: usize = 8;
+const RTT_UPDATE_FACTOR: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    offset: f64,
+    rtt: f64,
+}
+
+// Estimates how far a peer's clock is from ours from NTP style exchanges. The offset
+// comes from the fastest recent exchange since queueing delays only ever make a round
+// trip slower, and the slower a round trip the less its offset can be trusted.
+#[derive(Debug, Clone, Default)]
+pub struct ClockSync {
+    samples: VecDeque<Sample>,
+    offset: Option<f64>,
+    rtt: Option<f64>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Feeds in a finished exchange: `origin` and `now` are on our clock, `received`
+    // and `transmit` on the peer's.
+    pub fn record(&mut self, origin: f64, received: f64, transmit: f64, now: f64) {
+        let rtt = ((now - origin) - (transmit - received)).max(0.0);
+        let offset = ((received - origin) + (transmit - now)) / 2.0;
+        if self.samples.len() == SAMPLE_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample { offset, rtt });
+
+        self.rtt = Some(match self.rtt {
+            Some(smoothed) => smoothed + (rtt - smoothed) * RTT_UPDATE_FACTOR,
+            None => rtt,
+        });
+        self.offset = self
+            .samples
+            .iter()
+            .min_by(|a, b| a.rtt.partial_cmp(&b.rtt).unwrap())
+            .map(|sample| sample.offset);
+    }
+
+    // What to add to our clock to get the peer's, `None` until an exchange finished.
+    pub fn offset(&self) -> Option<f64> {
+        self.offset
+    }
+
+    pub fn rtt(&self) -> Option<f64> {
+        self.rtt
+    }
+
+    // The peer's clock right now, given ours.
+    pub fn remote_time(&self, local: f64) -> Option<f64> {
+        self.offset.map(|offset| local + offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFSET: f64 = 100.0;
+
+    // An exchange starting at `origin` on our clock, taking `up` to reach a peer whose
+    // clock is `OFFSET` ahead of ours and `down` to come back.
+    fn exchange(sync: &mut ClockSync, origin: f64, up: f64, down: f64) {
+        let received = origin + up + OFFSET;
+        let transmit = received + 1.0;
+        let now = transmit - OFFSET + down;
+        sync.record(origin, received, transmit, now);
+    }
+
+    #[test]
+    fn nothing_is_known_before_an_exchange() {
+        let sync = ClockSync::new();
+        assert_eq!(sync.offset(), None);
+        assert_eq!(sync.rtt(), None);
+        assert_eq!(sync.remote_time(0.0), None);
+    }
+
+    #[test]
+    fn offset_comes_from_the_fastest_exchange() {
+        let mut sync = ClockSync::new();
+        // Queued on the way there, so the offset looks 37.5 too large.
+        exchange(&mut sync, 0.0, 80.0, 5.0);
+        assert_eq!(sync.offset(), Some(OFFSET + 37.5));
+        exchange(&mut sync, 1000.0, 5.0, 5.0);
+        exchange(&mut sync, 2000.0, 5.0, 60.0);
+        assert_eq!(sync.offset(), Some(OFFSET));
+        assert_eq!(sync.remote_time(500.0), Some(500.0 + OFFSET));
+    }
+
+    #[test]
+    fn old_exchanges_fall_out_of_the_window() {
+        let mut sync = ClockSync::new();
+        exchange(&mut sync, 0.0, 5.0, 5.0);
+        for i in 0..SAMPLE_WINDOW {
+            exchange(&mut sync, (i + 1) as f64 * 1000.0, 25.0, 5.0);
+        }
+        assert_eq!(sync.offset(), Some(OFFSET + 10.0));
+    }
+
+    #[test]
+    fn rtt_is_smoothed_and_excludes_the_peers_processing_time() {
+        let mut sync = ClockSync::new();
+        exchange(&mut sync, 0.0, 10.0, 10.0);
+        assert_eq!(sync.rtt(), Some(20.0));
+        exchange(&mut sync, 1000.0, 60.0, 60.0);
+        assert_eq!(sync.rtt(), Some(20.0 + 100.0 * RTT_UPDATE_FACTOR));
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+// A piece of data attached to an entity. Components travel as opaque bytes keyed by
+// `NAME`, so adding one only means defining it here (or anywhere else) and registering
+// it with whoever needs to read it, the protocol doesn't change.
+pub trait Component: Serialize + DeserializeOwned + Clone {
+    const NAME: &'static str;
+}
+
+// Serialized component as it is stored on entities and sent over the wire.
+pub type ComponentData = Vec<u8>;
+
+pub fn encode<C: Component>(component: &C) -> ComponentData {
+    bincode::serialize(component).expect("components always serialize")
+}
+
+pub fn decode<C: Component>(data: &[u8]) -> Option<C> {
+    match bincode::deserialize(data) {
+        Ok(component) => Some(component),
+        Err(err) => {
+            tracing::warn!("Failed to decode {} component: {}", C::NAME, err);
+            None
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub position: (f32, f32),
+}
+
+impl Component for Transform {
+    const NAME: &'static str = "transform";
+}
+
+// How an entity is drawn.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Renderable {
+    pub shape: Shape,
+    pub size: f32,
+    pub color: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Tree,
+    Player,
+}
+
+impl Component for Renderable {
+    const NAME: &'static str = "renderable";
+}
+
+// Marks the entity a client controls.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PlayerInfo {
+    pub player: u32,
+}
+
+impl Component for PlayerInfo {
+    const NAME: &'static str = "player_info";
+}
+
+type Schema<T> = Box<dyn Fn(&[u8]) -> Option<T>>;
+
+// Turns serialized components back into something of type `T` by name, for code that
+// handles components it doesn't know statically, like handing them to JS.
+pub struct ComponentRegistry<T> {
+    schemas: HashMap<&'static str, Schema<T>>,
+}
+
+impl<T> Default for ComponentRegistry<T> {
+    fn default() -> Self {
+        Self {
+            schemas: HashMap::new(),
+        }
+    }
+}
+
+impl<T> ComponentRegistry<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<C, F>(&mut self, convert: F)
+    where
+        C: Component,
+        F: Fn(C) -> Option<T> + 'static,
+    {
+        self.schemas.insert(
+            C::NAME,
+            Box::new(move |data| decode::<C>(data).and_then(&convert)),
+        );
+    }
+
+    // `None` for components that aren't registered or don't decode.
+    pub fn convert(&self, name: &str, data: &[u8]) -> Option<T> {
+        self.schemas.get(name).and_then(|schema| schema(data))
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.schemas.keys().copied()
+    }
+}
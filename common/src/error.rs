@@ -0,0 +1,58 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetworkError {
+    // No connection with this id is registered with the multiplexer.
+    UnknownClient(usize),
+    // The connection's processor has shut down and stopped accepting messages.
+    ConnectionClosed(usize),
+    // The connection isn't keeping up and its queue is full, the message was dropped.
+    ChannelFull(usize),
+    // The peer sent something that couldn't be decoded.
+    Decode(usize, String),
+    // Nothing was heard from the peer within the idle timeout.
+    TimedOut(usize),
+}
+
+impl NetworkError {
+    pub fn connection(&self) -> usize {
+        match self {
+            NetworkError::UnknownClient(id)
+            | NetworkError::ConnectionClosed(id)
+            | NetworkError::ChannelFull(id)
+            | NetworkError::Decode(id, _)
+            | NetworkError::TimedOut(id) => *id,
+        }
+    }
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::UnknownClient(id) => write!(f, "no connection with id {}", id),
+            NetworkError::ConnectionClosed(id) => write!(f, "connection {} is closed", id),
+            NetworkError::ChannelFull(id) => write!(f, "connection {} can't keep up", id),
+            NetworkError::TimedOut(id) => write!(f, "connection {} timed out", id),
+            NetworkError::Decode(id, reason) => {
+                write!(
+                    f,
+                    "failed to decode packet on connection {}: {}",
+                    id, reason
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+pub(crate) fn try_send<T>(
+    id: usize,
+    sender: &async_channel::Sender<T>,
+    message: T,
+) -> Result<(), NetworkError> {
+    sender.try_send(message).map_err(|err| match err {
+        async_channel::TrySendError::Full(_) => NetworkError::ChannelFull(id),
+        async_channel::TrySendError::Closed(_) => NetworkError::ConnectionClosed(id),
+    })
+}
@@ -0,0 +1,70 @@
+use std::sync::{Arc, Mutex};
+
+use async_channel::{Receiver, Sender};
+
+use crate::error::NetworkError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisconnectReason {
+    // The connection was shut down with `ConnectionMultiplexer::kill`.
+    Killed,
+    // Nothing was heard from the peer within the idle timeout.
+    TimedOut,
+    // The connection failed, e.g. the peer sent something that couldn't be decoded.
+    Error(NetworkError),
+}
+
+impl From<NetworkError> for DisconnectReason {
+    fn from(err: NetworkError) -> Self {
+        match err {
+            NetworkError::TimedOut(_) => DisconnectReason::TimedOut,
+            err => DisconnectReason::Error(err),
+        }
+    }
+}
+
+// Everything that happens to a connection over its lifetime, in order. A connection
+// always ends with exactly one `Disconnected` or `ProcessorCrashed`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+    Registered(usize),
+    // The first message from the peer made it through, so both ends are talking.
+    Connected(usize),
+    Disconnected(usize, DisconnectReason),
+    // The connection's processor panicked. The connection stays registered until it
+    // is killed, but nothing will be sent or received on it.
+    ProcessorCrashed(usize, String),
+}
+
+impl ConnectionEvent {
+    pub fn connection(&self) -> usize {
+        match self {
+            ConnectionEvent::Registered(id)
+            | ConnectionEvent::Connected(id)
+            | ConnectionEvent::Disconnected(id, _)
+            | ConnectionEvent::ProcessorCrashed(id, _) => *id,
+        }
+    }
+}
+
+// Hands every event to every subscriber. Subscribers that went away are dropped the
+// next time something is published.
+#[derive(Clone, Default)]
+pub(crate) struct EventPublisher {
+    subscribers: Arc<Mutex<Vec<Sender<ConnectionEvent>>>>,
+}
+
+impl EventPublisher {
+    pub fn subscribe(&self) -> Receiver<ConnectionEvent> {
+        let (sender, receiver) = async_channel::unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn publish(&self, event: ConnectionEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.try_send(event.clone()).is_ok());
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use crate::{
+    clock::{Clock, ClockSync},
+    event::ConnectionEvent,
+    interest::{Interest, InterestManager},
+    message::{GameMessage, Message, Object, ReliableMessage, SignedMessage},
+    simulation::Simulation,
+    snapshot::{self, SnapshotHistory},
+    stats::TickStats,
+};
+
+// How often the host starts a clock exchange with every client and tells them how its
+// ticks went, in milliseconds.
+const SYNC_INTERVAL: f64 = 1000.0;
+// How close to an object a click has to land to count as clicking it.
+const CLICK_RADIUS: f32 = 10.0;
+
+#[derive(Debug, PartialEq)]
+enum ConnectionState {
+    Connecting,
+    Connected,
+}
+
+// The authoritative side of a game session: feeds client messages and connection
+// events into a `Simulation` and works out what every client needs to be sent each
+// tick. It never touches the network itself, callers drain `outgoing` and deliver the
+// messages however they like, so it runs the same behind a real server or in-process.
+pub struct GameHost {
+    simulation: Simulation,
+    // Tells this host's snapshots and acks apart from those of other rooms a client
+    // was in, see `ReliableMessage::JoinedRoom`.
+    epoch: u32,
+    history: SnapshotHistory,
+    interest: InterestManager,
+    clients: HashMap<usize, ConnectionState>,
+    clock: Clock,
+    // Each client's clock relative to ours.
+    client_clocks: HashMap<usize, ClockSync>,
+    last_sync: Option<f64>,
+    // Tick timings since they were last sent.
+    tick_stats: TickStats,
+    rng: SmallRng,
+    outgoing: Vec<SignedMessage<GameMessage>>,
+}
+
+impl GameHost {
+    pub fn new(simulation: Simulation, seed: u64) -> Self {
+        Self::for_room(simulation, seed, Interest::default(), 0)
+    }
+
+    // A host for one of many rooms, each of which needs its own `epoch`.
+    pub fn for_room(simulation: Simulation, seed: u64, interest: Interest, epoch: u32) -> Self {
+        Self {
+            simulation,
+            epoch,
+            history: SnapshotHistory::new(epoch),
+            interest: InterestManager::new(interest),
+            clients: HashMap::new(),
+            clock: Clock::new(),
+            client_clocks: HashMap::new(),
+            last_sync: None,
+            tick_stats: TickStats::default(),
+            rng: SmallRng::seed_from_u64(seed),
+            outgoing: Vec::new(),
+        }
+    }
+
+    pub fn simulation(&self) -> &Simulation {
+        &self.simulation
+    }
+
+    // Whoever calls `tick` on a schedule reports how long each took here, and how many
+    // it had to drop, so clients get to hear about it.
+    pub fn record_tick_time(&mut self, elapsed_ms: f32, budget_ms: f32) {
+        self.tick_stats.record(elapsed_ms, budget_ms);
+    }
+
+    pub fn record_skipped_ticks(&mut self, ticks: u32) {
+        self.tick_stats.record_skipped(ticks);
+    }
+
+    // Messages produced since the last call, in the order they should be sent.
+    pub fn outgoing(&mut self) -> Vec<SignedMessage<GameMessage>> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    pub fn handle_event(&mut self, event: ConnectionEvent) {
+        match event {
+            ConnectionEvent::Registered(id) => {
+                self.clients.insert(id, ConnectionState::Connecting);
+            }
+            ConnectionEvent::Connected(id) => {
+                tracing::info!("Client {} connected", id);
+                self.send(id, ReliableMessage::Connect);
+                self.send_to_connected(ReliableMessage::Connected(id.to_string()));
+                let color = format!("#{:06x}", self.rng.gen_range(0, 0x0100_0000));
+                self.simulation.add_player(id as u32, color);
+                self.clients.insert(id, ConnectionState::Connected);
+            }
+            ConnectionEvent::Disconnected(id, _) | ConnectionEvent::ProcessorCrashed(id, _) => {
+                tracing::info!("Client {} disconnected", id);
+                let state = self.clients.remove(&id);
+                self.simulation.remove_player(id as u32);
+                self.client_clocks.remove(&id);
+                self.history.remove_client(id);
+                self.interest.remove_client(id);
+                if state == Some(ConnectionState::Connected) {
+                    self.send_to_connected(ReliableMessage::Disconnected(id.to_string()));
+                }
+            }
+        }
+    }
+
+    pub fn handle_message(&mut self, message: SignedMessage<GameMessage>) {
+        let id = message.id;
+        match message.message {
+            GameMessage::Unreliable(Message::Sync(origin)) => {
+                let received = self.clock.now();
+                self.send(id, Message::SyncReply(origin, received, self.clock.now()));
+            }
+            GameMessage::Unreliable(Message::SyncReply(origin, received, transmit)) => {
+                let now = self.clock.now();
+                self.client_clocks
+                    .entry(id)
+                    .or_default()
+                    .record(origin, received, transmit, now);
+            }
+            GameMessage::Unreliable(Message::Position(x, y)) => {
+                let text = match self.simulation.index().nearest((x, y), CLICK_RADIUS) {
+                    Some(object) => format!("Client {} clicked object {}", id, object),
+                    None => format!("Client {} clicked {:?}", id, (x, y)),
+                };
+                self.send_to_connected(ReliableMessage::Text(text));
+            }
+            // Acks for another room's snapshots would have us diff against states
+            // this room never sent.
+            GameMessage::Unreliable(Message::Ack(epoch, tick)) => {
+                if epoch == self.epoch {
+                    self.history.ack(id, tick);
+                }
+            }
+            // Sequence numbers start over in every room, inputs meant for the last one
+            // would have ours ignore everything up to where that one left off.
+            GameMessage::Unreliable(Message::Input(epoch, inputs)) => {
+                if epoch == self.epoch {
+                    for input in &inputs {
+                        self.simulation.apply_input(id as u32, input);
+                    }
+                }
+            }
+            GameMessage::Unreliable(Message::State(_))
+            | GameMessage::Unreliable(Message::Delta(_))
+            | GameMessage::Unreliable(Message::InputAck(_, _))
+            | GameMessage::Unreliable(Message::TickStats(_))
+            | GameMessage::Unreliable(Message::Unknown)
+            | GameMessage::Unreliable(Message::Player(_, _))
+            | GameMessage::Reliable(_) => {}
+        }
+    }
+
+    // Steps the simulation and queues this tick's snapshot for every client, limited to
+    // what is around its player. Objects coming into or going out of view are announced
+    // on the reliable channel before the snapshot that includes or drops them. Whole
+    // snapshots go over the reliable channel too, they can outgrow an unreliable message.
+    pub fn tick(&mut self) {
+        self.simulation.step();
+        self.sync_clocks();
+
+        let now = self.clock.now();
+        let tick = self.simulation.tick();
+        for id in self.connected() {
+            let state = self.simulation.snapshot();
+            let center = self.simulation.player_position(id as u32);
+            let change = self.interest.update(id, center, self.simulation.index());
+            let spawned: Vec<Object> = change
+                .entered
+                .iter()
+                .filter_map(|object| state.get(object).cloned())
+                .collect();
+            let visible = self.interest.filter(id, state);
+            self.history.push(id, tick, now, visible);
+            if !spawned.is_empty() {
+                self.send(id, ReliableMessage::Spawn(spawned));
+            }
+            if !change.left.is_empty() {
+                self.send(id, ReliableMessage::Despawn(change.left));
+            }
+            if let Some(ack) = self.simulation.input_ack(id as u32) {
+                self.send(id, Message::InputAck(self.epoch, ack));
+            }
+            match self.history.delta_for(id) {
+                Some(delta) if delta.baseline.is_some() && snapshot::fits_unreliable(&delta) => {
+                    self.send(id, Message::Delta(delta));
+                }
+                Some(_) => {
+                    for part in self
+                        .history
+                        .full_for(id)
+                        .map(snapshot::split)
+                        .unwrap_or_default()
+                    {
+                        self.send(id, ReliableMessage::Snapshot(part));
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+
+    fn sync_clocks(&mut self) {
+        let now = self.clock.now();
+        if matches!(self.last_sync, Some(last) if now - last < SYNC_INTERVAL) {
+            return;
+        }
+        self.last_sync = Some(now);
+        self.send_to_connected(Message::Sync(now));
+        if self.tick_stats.ticks > 0 || self.tick_stats.skipped > 0 {
+            let mut stats = std::mem::take(&mut self.tick_stats);
+            stats.epoch = self.epoch;
+            stats.tick = self.simulation.tick();
+            self.send_to_connected(Message::TickStats(stats));
+        }
+    }
+
+    fn connected(&self) -> Vec<usize> {
+        self.clients
+            .iter()
+            .filter(|(_, state)| **state == ConnectionState::Connected)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    fn send<M: Into<GameMessage>>(&mut self, id: usize, message: M) {
+        self.outgoing.push(SignedMessage {
+            id,
+            message: message.into(),
+        });
+    }
+
+    fn send_to_connected<M: Into<GameMessage>>(&mut self, message: M) {
+        let message = message.into();
+        for id in self.connected() {
+            self.send(id, message.clone());
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{snapshot::WorldState, spatial::SpatialGrid};
+
+// Which part of the world around its player a client is told about.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interest {
+    // Everything, no matter how far away.
+    Everything,
+    // Objects within this distance of the player.
+    Radius(f32),
+    // Objects in the grid cell the player is in and `range` cells around it.
+    Grid { cell_size: f32, range: i32 },
+}
+
+impl Default for Interest {
+    fn default() -> Self {
+        Interest::Radius(300.0)
+    }
+}
+
+impl Interest {
+    pub fn contains(&self, center: (f32, f32), position: (f32, f32)) -> bool {
+        match *self {
+            Interest::Everything => true,
+            Interest::Radius(radius) => {
+                let (dx, dy) = (position.0 - center.0, position.1 - center.1);
+                dx * dx + dy * dy <= radius * radius
+            }
+            Interest::Grid { cell_size, range } => {
+                let cell = |value: f32| (value / cell_size).floor() as i32;
+                (cell(position.0) - cell(center.0)).abs() <= range
+                    && (cell(position.1) - cell(center.1)).abs() <= range
+            }
+        }
+    }
+
+    // Ids of the objects in `index` that are in scope from `center`.
+    pub fn query(&self, center: (f32, f32), index: &SpatialGrid) -> Vec<u32> {
+        let mut found = match *self {
+            Interest::Everything => return index.ids().collect(),
+            Interest::Radius(radius) => return index.in_radius(center, radius),
+            Interest::Grid { cell_size, range } => {
+                let reach = cell_size * (range + 1) as f32;
+                index.in_rect(
+                    (center.0 - reach, center.1 - reach),
+                    (center.0 + reach, center.1 + reach),
+                )
+            }
+        };
+        found.retain(
+            |id| matches!(index.position(*id), Some(position) if self.contains(center, position)),
+        );
+        found
+    }
+}
+
+// Objects that came into or went out of a client's scope since the last update.
+#[derive(Debug, Default)]
+pub struct ScopeChange {
+    pub entered: Vec<u32>,
+    pub left: Vec<u32>,
+}
+
+// Tracks which objects every client currently has in scope.
+#[derive(Default)]
+pub struct InterestManager {
+    interest: Interest,
+    scopes: HashMap<usize, HashSet<u32>>,
+}
+
+impl InterestManager {
+    pub fn new(interest: Interest) -> Self {
+        Self {
+            interest,
+            scopes: HashMap::new(),
+        }
+    }
+
+    // Recomputes the client's scope around `center`. A client without a center, one
+    // that has no player yet, sees nothing.
+    pub fn update(
+        &mut self,
+        client: usize,
+        center: Option<(f32, f32)>,
+        index: &SpatialGrid,
+    ) -> ScopeChange {
+        let visible: HashSet<u32> = match center {
+            Some(center) => self.interest.query(center, index).into_iter().collect(),
+            None => HashSet::new(),
+        };
+        let previous = self.scopes.insert(client, visible).unwrap_or_default();
+        let visible = &self.scopes[&client];
+        ScopeChange {
+            entered: visible.difference(&previous).copied().collect(),
+            left: previous.difference(visible).copied().collect(),
+        }
+    }
+
+    // The part of `state` the client has in scope.
+    pub fn filter(&self, client: usize, state: &WorldState) -> WorldState {
+        let scope = match self.scopes.get(&client) {
+            Some(scope) => scope,
+            None => return WorldState::new(),
+        };
+        state
+            .iter()
+            .filter(|(id, _)| scope.contains(id))
+            .map(|(id, object)| (*id, object.clone()))
+            .collect()
+    }
+
+    pub fn remove_client(&mut self, client: usize) {
+        self.scopes.remove(&client);
+    }
+}
@@ -1,6 +1,22 @@
 #![recursion_limit = "256"]
 pub mod buffer;
+pub mod clock;
+pub mod component;
+pub mod error;
+pub mod event;
+pub mod host;
+pub mod interest;
+pub mod loopback;
 pub mod message;
+pub mod movement;
 pub mod multiplexer;
 pub mod processor;
+pub mod protocol;
 pub mod runtime;
+pub mod session;
+pub mod simulation;
+pub mod snapshot;
+pub mod spatial;
+pub mod stats;
+pub mod udp;
+pub mod virtual_time;
@@ -0,0 +1,265 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_channel::{Receiver, Sender};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use crate::{
+    error::NetworkError,
+    message::{RawMessage, SignedMessage},
+    multiplexer::ConnectionMultiplexer,
+    protocol::Protocol,
+    runtime::Runtime,
+};
+
+// In-process stand-in for the WebRTC server and client. Packets emitted by a
+// multiplexer are pushed through a `LinkConditioner` and handed straight to the raw
+// channel of the multiplexer on the other end, so both sides can run in one process.
+
+#[derive(Debug, Clone)]
+pub struct LinkConditioner {
+    pub latency: Duration,
+    // Each packet is delayed by up to this much on top of `latency`, in either direction.
+    pub jitter: Duration,
+    pub loss: f64,
+    pub duplicate: f64,
+    // Chance a packet is held back for an extra `latency` so later packets overtake it.
+    pub reorder: f64,
+    pub seed: u64,
+}
+
+impl LinkConditioner {
+    pub fn perfect() -> Self {
+        Self {
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            seed: 0,
+        }
+    }
+
+    pub fn with_latency(mut self, latency: Duration, jitter: Duration) -> Self {
+        self.latency = latency;
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_loss(mut self, loss: f64) -> Self {
+        self.loss = loss;
+        self
+    }
+
+    pub fn with_duplicate(mut self, duplicate: f64) -> Self {
+        self.duplicate = duplicate;
+        self
+    }
+
+    pub fn with_reorder(mut self, reorder: f64) -> Self {
+        self.reorder = reorder;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    fn is_perfect(&self) -> bool {
+        self.latency == Duration::from_millis(0)
+            && self.jitter == Duration::from_millis(0)
+            && self.loss <= 0.0
+            && self.duplicate <= 0.0
+            && self.reorder <= 0.0
+    }
+}
+
+impl Default for LinkConditioner {
+    fn default() -> Self {
+        Self::perfect()
+    }
+}
+
+// One direction of a conditioned link.
+struct Link {
+    conditioner: LinkConditioner,
+    rng: SmallRng,
+}
+
+impl Link {
+    fn new(conditioner: LinkConditioner, stream: u64) -> Self {
+        let rng =
+            SmallRng::seed_from_u64(conditioner.seed ^ stream.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        Self { conditioner, rng }
+    }
+
+    // Returns the delay for every copy of the packet that survives the link.
+    fn schedule(&mut self) -> Vec<Duration> {
+        if self.chance(self.conditioner.loss) {
+            return vec![];
+        }
+        let mut delays = vec![self.delay()];
+        if self.chance(self.conditioner.duplicate) {
+            delays.push(self.delay());
+        }
+        delays
+    }
+
+    fn delay(&mut self) -> Duration {
+        let jitter = self.conditioner.jitter.as_secs_f64();
+        let mut delay = self.conditioner.latency.as_secs_f64();
+        if jitter > 0.0 {
+            delay += self.rng.gen_range(-jitter, jitter);
+        }
+        if self.chance(self.conditioner.reorder) {
+            delay += self.conditioner.latency.as_secs_f64().max(jitter);
+        }
+        Duration::from_secs_f64(delay.max(0.0))
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        self.rng.gen_bool(probability.clamp(0.0, 1.0))
+    }
+}
+
+async fn deliver<R>(runtime: &R, link: &mut Link, sender: &Sender<RawMessage>, packet: RawMessage)
+where
+    R: Runtime + 'static,
+{
+    if link.conditioner.is_perfect() {
+        // Keep ordering intact when there is nothing to simulate.
+        if sender.send(packet).await.is_err() {
+            tracing::info!("Loopback peer went away, dropping packet");
+        }
+        return;
+    }
+    for delay in link.schedule() {
+        let runtime_inner = runtime.clone();
+        let sender = sender.clone();
+        let packet = packet.clone();
+        runtime.spawn(async move {
+            runtime_inner.sleep(delay).await;
+            let _ = sender.send(packet).await;
+        });
+    }
+}
+
+type Routes = Arc<Mutex<HashMap<usize, Sender<RawMessage>>>>;
+
+pub struct LoopbackNetwork<R>
+where
+    R: Runtime + 'static,
+{
+    runtime: R,
+    conditioner: LinkConditioner,
+    server_sender: Sender<SignedMessage<RawMessage>>,
+    routes: Routes,
+    links: u64,
+}
+
+pub struct LoopbackClient<R, P>
+where
+    R: Runtime + 'static,
+    P: Protocol,
+{
+    pub multiplexer: ConnectionMultiplexer<R, P>,
+    // Id of this client's connection inside the server multiplexer.
+    pub server_id: usize,
+    // Id of the connection to the server inside `multiplexer`.
+    pub client_id: usize,
+}
+
+impl<R> LoopbackNetwork<R>
+where
+    R: Runtime + 'static,
+{
+    pub fn new(runtime: R, conditioner: LinkConditioner) -> Self {
+        let (server_sender, server_receiver) = async_channel::unbounded();
+        let routes: Routes = Arc::new(Mutex::new(HashMap::new()));
+        let network = Self {
+            runtime: runtime.clone(),
+            conditioner: conditioner.clone(),
+            server_sender,
+            routes: routes.clone(),
+            links: 1,
+        };
+        let runtime_inner = runtime.clone();
+        runtime.spawn(async move {
+            let mut link = Link::new(conditioner, 0);
+            Self::route_downstream(runtime_inner, &mut link, server_receiver, routes).await;
+        });
+        network
+    }
+
+    // The packet sender to build the server-side multiplexer with.
+    pub fn server_sender(&self) -> Sender<SignedMessage<RawMessage>> {
+        self.server_sender.clone()
+    }
+
+    // Creates a client multiplexer with a single connection wired to a freshly
+    // registered connection on `server`.
+    pub fn connect<P>(&mut self, server: &mut ConnectionMultiplexer<R, P>) -> LoopbackClient<R, P>
+    where
+        P: Protocol,
+    {
+        let (client_sender, client_receiver) = async_channel::unbounded();
+        let mut multiplexer = ConnectionMultiplexer::new(self.runtime.clone(), client_sender);
+        let client_id = multiplexer.register();
+        let server_id = server.register();
+
+        let downstream = multiplexer
+            .get_raw_channel(client_id)
+            .expect("connection was just registered");
+        self.routes.lock().unwrap().insert(server_id, downstream);
+
+        let upstream = server
+            .get_raw_channel(server_id)
+            .expect("connection was just registered");
+        let runtime = self.runtime.clone();
+        let mut link = Link::new(self.conditioner.clone(), self.links);
+        self.links += 1;
+        self.runtime.spawn(async move {
+            while let Ok(packet) = client_receiver.recv().await {
+                deliver(&runtime, &mut link, &upstream, packet.message).await;
+            }
+        });
+
+        LoopbackClient {
+            multiplexer,
+            server_id,
+            client_id,
+        }
+    }
+
+    pub fn disconnect<P>(
+        &mut self,
+        server: &mut ConnectionMultiplexer<R, P>,
+        client: &mut LoopbackClient<R, P>,
+    ) -> Result<(), NetworkError>
+    where
+        P: Protocol,
+    {
+        self.routes.lock().unwrap().remove(&client.server_id);
+        server.kill(client.server_id)?;
+        client.multiplexer.kill(client.client_id)
+    }
+
+    async fn route_downstream(
+        runtime: R,
+        link: &mut Link,
+        receiver: Receiver<SignedMessage<RawMessage>>,
+        routes: Routes,
+    ) {
+        while let Ok(packet) = receiver.recv().await {
+            let route = routes.lock().unwrap().get(&packet.id).cloned();
+            match route {
+                Some(sender) => deliver(&runtime, link, &sender, packet.message).await,
+                None => tracing::info!("No loopback client for connection {}", packet.id),
+            }
+        }
+    }
+}
@@ -1,29 +1,64 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use instant::Duration;
-use turbulence::{reliable_channel, MessageChannelMode};
-#[derive(serde::Serialize, serde::Deserialize, Debug, Copy, Clone)]
-pub struct Tree {
-    pub position: (f32, f32),
-    pub size: f32,
-}
+use turbulence::{
+    message_channels::ChannelAlreadyRegistered, reliable_channel, MessageChannelMode,
+    MessageChannels, MessageChannelsBuilder, PacketPool,
+};
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
-pub struct Player {
-    pub position: (f32, f32),
-    pub color: String,
+use crate::{
+    component::{self, Component, ComponentData, Transform},
+    movement::{InputAck, InputCommand},
+    protocol::Protocol,
+    snapshot::{SnapshotDelta, SnapshotPart},
+    stats::TickStats,
+};
+// An entity: an id and whatever components are attached to it, keyed by name.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Object {
+    pub id: u32,
+    pub components: BTreeMap<String, ComponentData>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
-pub enum ObjectInfo {
-    Tree(Tree),
-    Player(Player),
-}
+impl Object {
+    pub fn new(id: u32) -> Self {
+        Self {
+            id,
+            components: BTreeMap::new(),
+        }
+    }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
-pub struct Object {
-    pub id: u32,
-    pub object_info: ObjectInfo,
+    pub fn with<C: Component>(mut self, component: C) -> Self {
+        self.insert(component);
+        self
+    }
+
+    pub fn get<C: Component>(&self) -> Option<C> {
+        self.components
+            .get(C::NAME)
+            .and_then(|data| component::decode(data))
+    }
+
+    pub fn has<C: Component>(&self) -> bool {
+        self.components.contains_key(C::NAME)
+    }
+
+    pub fn insert<C: Component>(&mut self, component: C) {
+        self.components
+            .insert(C::NAME.to_string(), component::encode(&component));
+    }
+
+    pub fn remove<C: Component>(&mut self) {
+        self.components.remove(C::NAME);
+    }
+
+    pub fn position(&self) -> Option<(f32, f32)> {
+        self.get::<Transform>().map(|transform| transform.position)
+    }
+
+    pub fn set_position(&mut self, position: (f32, f32)) {
+        self.insert(Transform { position });
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
@@ -33,10 +68,24 @@ pub struct GameState {
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub enum Message {
-    Sync,
+    // Asks the peer for its time, carries the sender's clock when it was sent.
+    Sync(f64),
+    // Answers a `Sync` with (the request's time, when it arrived, when the reply was
+    // sent). The last two are on the replying side's clock.
+    SyncReply(f64, f64, f64),
     Position(f32, f32),
     Player(u32, (f32, f32)),
     State(Object),
+    // The world as of a server tick, relative to a state the client is known to have.
+    Delta(SnapshotDelta),
+    // The client has rebuilt the world of the room with this epoch as of this tick.
+    Ack(u32, u64),
+    // The client's latest inputs for the room with this epoch, including ones it already
+    // sent that weren't acked.
+    Input(u32, Vec<InputCommand>),
+    // Acks input on behalf of the room with this epoch.
+    InputAck(u32, InputAck),
+    TickStats(TickStats),
     Unknown,
 }
 
@@ -47,6 +96,19 @@ pub enum ReliableMessage {
     State(HashMap<u32, Object>),
     Text(String),
     Connect,
+    // Objects that came into the client's area of interest.
+    Spawn(Vec<Object>),
+    // Ids of objects that went out of it.
+    Despawn(Vec<u32>),
+    // Asks the server to put the client in the room with this code, or in whichever
+    // room it sees fit when there's none.
+    JoinRoom(Option<String>),
+    // Tells the client which room it is in and that room's epoch. Everything after it
+    // comes from that room, but unreliable messages from the old one may still trail in,
+    // hence the epoch to tell them apart.
+    JoinedRoom(String, u32),
+    // Part of a whole-world snapshot, too big to trust to the unreliable channel.
+    Snapshot(SnapshotPart),
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
@@ -95,4 +157,80 @@ pub const RELIABLE_MESSAGE_SETTINGS: turbulence::MessageChannelSettings =
         packet_buffer_size: 64,
     };
 
+// Channel the processors use to talk among themselves (pings for RTT and loss). A
+// `Protocol` must not register anything on it.
+pub const CONTROL_CHANNEL: turbulence::PacketChannel = 255;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub(crate) enum ControlMessage {
+    Ping(u32),
+    // Echoes the ping id along with how many packets the sender has received so far.
+    Pong(u32, u64),
+}
+
+pub(crate) const CONTROL_MESSAGE_SETTINGS: turbulence::MessageChannelSettings =
+    turbulence::MessageChannelSettings {
+        channel: CONTROL_CHANNEL,
+        channel_mode: turbulence::MessageChannelMode::Unreliable,
+        message_buffer_size: 8,
+        packet_buffer_size: 8,
+    };
+
 pub type RawMessage = Box<[u8]>;
+
+#[derive(Debug, Clone)]
+pub enum GameMessage {
+    Unreliable(Message),
+    Reliable(ReliableMessage),
+}
+
+impl From<Message> for GameMessage {
+    fn from(message: Message) -> Self {
+        GameMessage::Unreliable(message)
+    }
+}
+
+impl From<ReliableMessage> for GameMessage {
+    fn from(message: ReliableMessage) -> Self {
+        GameMessage::Reliable(message)
+    }
+}
+
+// The protocol spoken between the game server and the wasm client.
+pub struct GameProtocol;
+
+impl Protocol for GameProtocol {
+    type Message = GameMessage;
+
+    fn register<R, P>(
+        builder: &mut MessageChannelsBuilder<R, P>,
+    ) -> Result<(), ChannelAlreadyRegistered>
+    where
+        R: turbulence::Runtime + 'static,
+        P: PacketPool + Clone + Send + 'static,
+        P::Packet: Unpin + Send,
+    {
+        builder.register::<Message>(MESSAGE_SETTINGS)?;
+        builder.register::<ReliableMessage>(RELIABLE_MESSAGE_SETTINGS)?;
+        Ok(())
+    }
+
+    fn send(channels: &mut MessageChannels, message: GameMessage) -> Option<GameMessage> {
+        match message {
+            GameMessage::Unreliable(message) => channels.send(message).map(GameMessage::Unreliable),
+            GameMessage::Reliable(message) => channels.send(message).map(GameMessage::Reliable),
+        }
+    }
+
+    fn recv(channels: &mut MessageChannels) -> Option<GameMessage> {
+        channels
+            .recv::<ReliableMessage>()
+            .map(GameMessage::Reliable)
+            .or_else(|| channels.recv::<Message>().map(GameMessage::Unreliable))
+    }
+
+    fn flush(channels: &mut MessageChannels) {
+        channels.flush::<ReliableMessage>();
+        channels.flush::<Message>();
+    }
+}
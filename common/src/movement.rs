@@ -0,0 +1,204 @@
+use std::collections::VecDeque;
+
+// Movement rules shared by the server, which runs them authoritatively, and clients,
+// which run them ahead of the server to predict where their own player is.
+
+// In world units per second.
+pub const PLAYER_SPEED: f32 = 100.0;
+// Longest a single input may move a player for, in milliseconds. Keeps one input from
+// jumping a player across the map, the server also caps the time a player's inputs
+// add up to by how long it has simulated.
+pub const MAX_INPUT_DT: f32 = 100.0;
+// How many unacknowledged inputs ride along with every new one, so a lost packet
+// doesn't lose movement.
+const INPUT_REDUNDANCY: usize = 8;
+// Most unacknowledged inputs kept to replay on top of an ack, a few seconds' worth.
+// Without acks, say while no room will have us, the oldest are dropped.
+const MAX_PENDING_INPUTS: usize = 128;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PlayerMovement {
+    pub position: (f32, f32),
+    pub target: (f32, f32),
+}
+
+impl PlayerMovement {
+    pub fn new(position: (f32, f32)) -> Self {
+        Self {
+            position,
+            target: position,
+        }
+    }
+
+    // Retargets if the input asks to, then moves towards the target for the input's
+    // frame time.
+    pub fn apply(&mut self, input: &InputCommand) {
+        if let Some(target) = input.target {
+            self.target = target;
+        }
+        let dt = if input.dt.is_finite() {
+            input.dt.clamp(0.0, MAX_INPUT_DT)
+        } else {
+            0.0
+        };
+        let max_distance = PLAYER_SPEED * dt / 1000.0;
+        let dx = self.target.0 - self.position.0;
+        let dy = self.target.1 - self.position.1;
+        let distance = (dx * dx + dy * dy).sqrt();
+        if distance <= max_distance {
+            self.position = self.target;
+        } else {
+            self.position.0 += dx / distance * max_distance;
+            self.position.1 += dy / distance * max_distance;
+        }
+    }
+}
+
+// One client frame worth of input.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct InputCommand {
+    pub sequence: u32,
+    // Where the player clicked this frame, if they did.
+    pub target: Option<(f32, f32)>,
+    // Frame time in milliseconds.
+    pub dt: f32,
+}
+
+// Sent to each client every tick: the last input the server applied for it and where
+// that left the player.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct InputAck {
+    // Id of the player's object in the world state.
+    pub object: u32,
+    pub sequence: u32,
+    pub movement: PlayerMovement,
+}
+
+// Client side. Applies inputs locally as soon as they are made and, whenever the server
+// reports where the player really is, replays the inputs it hasn't processed yet on
+// top of that.
+#[derive(Debug, Clone)]
+pub struct Prediction {
+    movement: PlayerMovement,
+    pending: VecDeque<InputCommand>,
+    next_sequence: u32,
+    last_acked: Option<u32>,
+}
+
+impl Prediction {
+    pub fn new(movement: PlayerMovement) -> Self {
+        Self {
+            movement,
+            pending: VecDeque::new(),
+            next_sequence: 1,
+            last_acked: None,
+        }
+    }
+
+    // Applies a new input and returns every input the server still needs, oldest
+    // first, ready to be sent.
+    pub fn input(&mut self, target: Option<(f32, f32)>, dt: f32) -> Vec<InputCommand> {
+        let input = InputCommand {
+            sequence: self.next_sequence,
+            target,
+            dt,
+        };
+        self.next_sequence += 1;
+        self.movement.apply(&input);
+        if self.pending.len() == MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(input);
+        self.pending
+            .iter()
+            .rev()
+            .take(INPUT_REDUNDANCY)
+            .rev()
+            .copied()
+            .collect()
+    }
+
+    pub fn reconcile(&mut self, ack: &InputAck) {
+        // Acks can arrive out of order, an older one would rewind us.
+        if matches!(self.last_acked, Some(last) if ack.sequence < last) {
+            return;
+        }
+        self.last_acked = Some(ack.sequence);
+        while matches!(self.pending.front(), Some(input) if input.sequence <= ack.sequence) {
+            self.pending.pop_front();
+        }
+        self.movement = ack.movement;
+        for input in &self.pending {
+            self.movement.apply(input);
+        }
+    }
+
+    pub fn movement(&self) -> PlayerMovement {
+        self.movement
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ack(sequence: u32, position: (f32, f32), target: (f32, f32)) -> InputAck {
+        InputAck {
+            object: 0,
+            sequence,
+            movement: PlayerMovement { position, target },
+        }
+    }
+
+    #[test]
+    fn inputs_beyond_the_ack_are_replayed() {
+        let mut prediction = Prediction::new(PlayerMovement::new((0.0, 0.0)));
+        prediction.input(Some((1000.0, 0.0)), 100.0);
+        prediction.input(None, 100.0);
+        prediction.input(None, 100.0);
+        assert_eq!(prediction.movement().position, (30.0, 0.0));
+
+        // The server only got the first input and it moved us less than we thought.
+        prediction.reconcile(&ack(1, (5.0, 0.0), (1000.0, 0.0)));
+        assert_eq!(prediction.movement().position, (25.0, 0.0));
+
+        // Once everything is acked, the server's word is final.
+        prediction.reconcile(&ack(3, (25.0, 0.0), (1000.0, 0.0)));
+        assert_eq!(prediction.movement().position, (25.0, 0.0));
+        let resent = prediction.input(None, 0.0);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].sequence, 4);
+    }
+
+    #[test]
+    fn older_acks_are_ignored() {
+        let mut prediction = Prediction::new(PlayerMovement::new((0.0, 0.0)));
+        prediction.input(Some((1000.0, 0.0)), 100.0);
+        prediction.input(None, 100.0);
+        prediction.reconcile(&ack(2, (20.0, 0.0), (1000.0, 0.0)));
+        prediction.reconcile(&ack(1, (10.0, 0.0), (1000.0, 0.0)));
+        assert_eq!(prediction.movement().position, (20.0, 0.0));
+    }
+
+    #[test]
+    fn unacked_inputs_are_resent_up_to_the_redundancy() {
+        let mut prediction = Prediction::new(PlayerMovement::new((0.0, 0.0)));
+        let mut sent = Vec::new();
+        for _ in 0..20 {
+            sent = prediction.input(None, 16.0);
+        }
+        let sequences: Vec<u32> = sent.iter().map(|input| input.sequence).collect();
+        assert_eq!(sequences, (13..=20).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn pending_inputs_are_capped_without_acks() {
+        let mut prediction = Prediction::new(PlayerMovement::new((0.0, 0.0)));
+        for _ in 0..MAX_PENDING_INPUTS * 3 {
+            prediction.input(None, 16.0);
+        }
+        assert_eq!(prediction.pending.len(), MAX_PENDING_INPUTS);
+        let oldest = prediction.pending.front().unwrap().sequence as usize;
+        assert_eq!(oldest, MAX_PENDING_INPUTS * 2 + 1);
+    }
+}
@@ -1,38 +1,55 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, panic::AssertUnwindSafe};
 
 use async_channel::{Receiver, Sender};
+use futures_util::FutureExt;
 
 use crate::{
+    error::{self, NetworkError},
+    event::{ConnectionEvent, DisconnectReason, EventPublisher},
+    message::GameProtocol,
     message::InternalMessage,
     message::RawMessage,
+    message::SignedMessage,
     message::Target,
-    message::{Message, ReliableMessage, SignedMessage},
     processor::ChannelBundle,
+    processor::ConnectionConfig,
     processor::MessageProcessorFactory,
+    protocol::Protocol,
     runtime::Runtime,
+    stats::ConnectionStats,
 };
 
-pub struct ConnectionMultiplexer<R>
+pub struct ConnectionMultiplexer<R, P = GameProtocol>
 where
     R: Runtime + 'static,
+    P: Protocol,
 {
     next_id: usize,
 
     runtime: R,
-    connections: HashMap<usize, ChannelBundle>,
-    processor_factory: MessageProcessorFactory<R>,
+    connections: HashMap<usize, ChannelBundle<P::Message>>,
+    processor_factory: MessageProcessorFactory<R, P>,
 
-    reliable_message_receiver: Receiver<SignedMessage<ReliableMessage>>,
-    message_receiver: Receiver<SignedMessage<Message>>,
+    message_receiver: Receiver<SignedMessage<P::Message>>,
+    events: EventPublisher,
 }
 
-impl<R> ConnectionMultiplexer<R>
+impl<R, P> ConnectionMultiplexer<R, P>
 where
     R: Runtime,
+    P: Protocol,
 {
     pub fn new(runtime: R, packet_sender: Sender<SignedMessage<RawMessage>>) -> Self {
+        Self::with_config(runtime, packet_sender, ConnectionConfig::default())
+    }
+
+    pub fn with_config(
+        runtime: R,
+        packet_sender: Sender<SignedMessage<RawMessage>>,
+        config: ConnectionConfig,
+    ) -> Self {
         let (message_sender, message_receiver) = async_channel::unbounded();
-        let (reliable_message_sender, reliable_message_receiver) = async_channel::unbounded();
+        let events = EventPublisher::default();
         Self {
             next_id: 1,
             runtime: runtime.clone(),
@@ -40,11 +57,12 @@ where
                 runtime,
                 packet_sender,
                 message_sender,
-                reliable_message_sender,
+                config,
+                events.clone(),
             ),
             connections: HashMap::new(),
             message_receiver,
-            reliable_message_receiver,
+            events,
         }
     }
 
@@ -54,140 +72,235 @@ where
         self.next_id += 1;
         let processor = self.processor_factory.build(id);
         let bundle = processor.channel_bundle();
+        self.events.publish(ConnectionEvent::Registered(id));
+        let events = self.events.clone();
         self.runtime.spawn(async move {
-            processor.run().await;
+            let event = match AssertUnwindSafe(processor.run()).catch_unwind().await {
+                Ok(Ok(())) => ConnectionEvent::Disconnected(id, DisconnectReason::Killed),
+                Ok(Err(err)) => {
+                    tracing::warn!("Message processor {} shut down: {}", id, err);
+                    ConnectionEvent::Disconnected(id, err.into())
+                }
+                Err(panic) => {
+                    let message = panic
+                        .downcast_ref::<&str>()
+                        .map(|message| message.to_string())
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_default();
+                    tracing::error!("Message processor {} crashed: {}", id, message);
+                    ConnectionEvent::ProcessorCrashed(id, message)
+                }
+            };
+            events.publish(event);
         });
         self.connections.insert(id, bundle);
         id
     }
 
-    pub async fn send_message(&self, target: Target, message: Message) -> Result<(), ()> {
+    // Queues a message for one or all connections. Broadcasts still reach every healthy
+    // connection when some of them fail, and report the first failure.
+    pub fn send_message<M>(&self, target: Target, message: M) -> Result<(), NetworkError>
+    where
+        M: Into<P::Message>,
+    {
+        let message = message.into();
         match target {
             Target::All => {
-                for client in self.connections.keys() {
-                    self.connections
-                        .get(client)
-                        .unwrap()
-                        .message_sender
-                        .send(message.clone())
-                        .await
-                        .unwrap();
+                let mut result = Ok(());
+                for (id, connection) in &self.connections {
+                    let sent = error::try_send(*id, &connection.message_sender, message.clone());
+                    result = result.and(sent);
                 }
+                result
             }
             Target::Client(client) => {
-                if !self.connections.contains_key(&client) {
-                    return Err(());
-                }
-                self.connections
-                    .get(&client)
-                    .unwrap()
-                    .message_sender
-                    .send(message)
-                    .await
-                    .unwrap();
+                let connection = self.connection(client)?;
+                error::try_send(client, &connection.message_sender, message)
             }
         }
-        Ok(())
     }
 
-    pub async fn send_reliable_message(
-        &self,
-        target: Target,
-        message: ReliableMessage,
-    ) -> Result<(), ()> {
+    pub fn send_raw(&self, target: Target, message: RawMessage) -> Result<(), NetworkError> {
         match target {
             Target::All => {
-                for client in self.connections.keys() {
-                    self.connections
-                        .get(client)
-                        .unwrap()
-                        .reliable_message_sender
-                        .send(message.clone())
-                        .await
-                        .unwrap();
+                let mut result = Ok(());
+                for (id, connection) in &self.connections {
+                    let sent = error::try_send(*id, &connection.byte_sender, message.clone());
+                    result = result.and(sent);
                 }
+                result
             }
             Target::Client(client) => {
-                if !self.connections.contains_key(&client) {
-                    return Err(());
-                }
-                self.connections
-                    .get(&client)
-                    .unwrap()
-                    .reliable_message_sender
-                    .send(message)
-                    .await
-                    .unwrap();
+                let connection = self.connection(client)?;
+                error::try_send(client, &connection.byte_sender, message)
             }
         }
-        Ok(())
     }
 
-    pub async fn send_raw(&self, target: Target, message: RawMessage) -> Result<(), ()> {
-        match target {
-            Target::All => {
-                for client in self.connections.keys() {
-                    self.connections
-                        .get(client)
-                        .unwrap()
-                        .byte_sender
-                        .send(message.clone())
-                        .await
-                        .unwrap();
-                }
-            }
-            Target::Client(client) => {
-                if !self.connections.contains_key(&client) {
-                    return Err(());
-                }
-                self.connections
-                    .get(&client)
-                    .unwrap()
-                    .byte_sender
-                    .send(message)
-                    .await
-                    .unwrap();
-            }
-        }
+    pub fn get_message_channel(&self, target: usize) -> Result<Sender<P::Message>, NetworkError> {
+        Ok(self.connection(target)?.message_sender.clone())
+    }
+
+    pub fn get_raw_channel(&self, target: usize) -> Result<Sender<RawMessage>, NetworkError> {
+        Ok(self.connection(target)?.byte_sender.clone())
+    }
+
+    pub fn stats(&self, target: usize) -> Result<ConnectionStats, NetworkError> {
+        Ok(self.connection(target)?.stats.lock().unwrap().clone())
+    }
+
+    pub fn message_receiver(&self) -> Receiver<SignedMessage<P::Message>> {
+        self.message_receiver.clone()
+    }
+
+    // Every subscriber gets its own copy of each lifecycle event from the moment it
+    // subscribes.
+    pub fn subscribe(&self) -> Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn kill(&mut self, target: usize) -> Result<(), NetworkError> {
+        tracing::info!("Killing multiplexer with id {}", target);
+        let connection = self
+            .connections
+            .remove(&target)
+            .ok_or(NetworkError::UnknownClient(target))?;
+        // A processor that already stopped on its own has nothing left to shut down.
+        let _ = connection
+            .internal_sender
+            .try_send(InternalMessage::Shutdown);
         Ok(())
     }
 
-    pub fn get_message_channel(&self, target: usize) -> Sender<Message> {
+    fn connection(&self, target: usize) -> Result<&ChannelBundle<P::Message>, NetworkError> {
         self.connections
             .get(&target)
-            .unwrap()
-            .message_sender
-            .clone()
+            .ok_or(NetworkError::UnknownClient(target))
     }
+}
 
-    pub fn get_reliable_message_channel(&self, target: usize) -> Sender<ReliableMessage> {
-        self.connections
-            .get(&target)
-            .unwrap()
-            .reliable_message_sender
-            .clone()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{message::Message, processor::CONNECTION_QUEUE_SIZE, virtual_time::VirtualRuntime};
+
+    // A message on the unreliable game channel that doesn't decode to anything.
+    const GARBAGE: &[u8] = &[1, 0xff, 0xff, 0xff, 0xff];
+
+    type Multiplexer = ConnectionMultiplexer<VirtualRuntime, GameProtocol>;
+
+    // Packets the multiplexer emits go nowhere, keep the receiver alive to let them.
+    fn setup() -> (
+        VirtualRuntime,
+        Multiplexer,
+        Receiver<SignedMessage<RawMessage>>,
+    ) {
+        let runtime = VirtualRuntime::new();
+        let (packets, unsent) = async_channel::unbounded();
+        let multiplexer = ConnectionMultiplexer::new(runtime.clone(), packets);
+        (runtime, multiplexer, unsent)
     }
 
-    pub fn get_raw_channel(&self, target: usize) -> Sender<RawMessage> {
-        self.connections.get(&target).unwrap().byte_sender.clone()
+    fn disconnected(
+        runtime: &VirtualRuntime,
+        events: &Receiver<ConnectionEvent>,
+    ) -> (usize, DisconnectReason) {
+        runtime.block_on(async {
+            loop {
+                match events.recv().await.unwrap() {
+                    ConnectionEvent::Disconnected(id, reason) => break (id, reason),
+                    _ => continue,
+                }
+            }
+        })
     }
 
-    pub fn reliable_message_receiver(&self) -> Receiver<SignedMessage<ReliableMessage>> {
-        self.reliable_message_receiver.clone()
+    #[test]
+    fn unknown_clients_are_reported() {
+        let (_runtime, mut multiplexer, _unsent) = setup();
+        let unknown = Err(NetworkError::UnknownClient(7));
+        assert_eq!(
+            multiplexer.send_message(Target::Client(7), Message::Unknown),
+            unknown
+        );
+        assert_eq!(
+            multiplexer.send_raw(Target::Client(7), GARBAGE.into()),
+            unknown
+        );
+        assert_eq!(
+            multiplexer.get_raw_channel(7).err(),
+            Some(NetworkError::UnknownClient(7))
+        );
+        assert_eq!(
+            multiplexer.stats(7).err(),
+            Some(NetworkError::UnknownClient(7))
+        );
+        assert_eq!(multiplexer.kill(7), unknown);
     }
 
-    pub fn message_receiver(&self) -> Receiver<SignedMessage<Message>> {
-        self.message_receiver.clone()
+    #[test]
+    fn killing_twice_reports_the_second_time() {
+        let (runtime, mut multiplexer, _unsent) = setup();
+        let events = multiplexer.subscribe();
+        let id = multiplexer.register();
+        assert_eq!(multiplexer.kill(id), Ok(()));
+        assert_eq!(multiplexer.kill(id), Err(NetworkError::UnknownClient(id)));
+        assert_eq!(
+            disconnected(&runtime, &events),
+            (id, DisconnectReason::Killed)
+        );
     }
 
-    pub fn kill(&mut self, target: usize) {
-        tracing::info!("Killing multiplexer with id {}", target);
-        if let Some(connection) = self.connections.get(&target) {
-            connection
-                .internal_sender
-                .try_send(InternalMessage::Shutdown)
+    #[test]
+    fn full_queues_reject_messages() {
+        let (_runtime, mut multiplexer, _unsent) = setup();
+        let id = multiplexer.register();
+        // The processor never gets to run, so nothing is taken off the queues.
+        for _ in 0..CONNECTION_QUEUE_SIZE {
+            multiplexer
+                .send_message(Target::Client(id), Message::Unknown)
+                .unwrap();
+            multiplexer
+                .send_raw(Target::Client(id), GARBAGE.into())
                 .unwrap();
-            self.connections.remove(&target);
         }
+        let full = Err(NetworkError::ChannelFull(id));
+        assert_eq!(
+            multiplexer.send_message(Target::Client(id), Message::Unknown),
+            full
+        );
+        assert_eq!(multiplexer.send_raw(Target::All, GARBAGE.into()), full);
+    }
+
+    #[test]
+    fn undecodable_packets_drop_only_their_connection() {
+        let (runtime, mut multiplexer, _unsent) = setup();
+        let events = multiplexer.subscribe();
+        let broken = multiplexer.register();
+        let healthy = multiplexer.register();
+        multiplexer
+            .send_raw(Target::Client(broken), GARBAGE.into())
+            .unwrap();
+        let (id, reason) = disconnected(&runtime, &events);
+        assert_eq!(id, broken);
+        assert!(matches!(
+            reason,
+            DisconnectReason::Error(NetworkError::Decode(id, _)) if id == broken
+        ));
+
+        // Until it's killed the connection is still known, just closed.
+        assert_eq!(
+            multiplexer.send_message(Target::Client(broken), Message::Unknown),
+            Err(NetworkError::ConnectionClosed(broken))
+        );
+        assert_eq!(
+            multiplexer.send_message(Target::All, Message::Unknown),
+            Err(NetworkError::ConnectionClosed(broken))
+        );
+        assert_eq!(
+            multiplexer.send_message(Target::Client(healthy), Message::Unknown),
+            Ok(())
+        );
+        assert_eq!(multiplexer.kill(broken), Ok(()));
     }
 }
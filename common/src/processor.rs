@@ -1,16 +1,28 @@
-use std::sync::Arc;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use async_channel::Sender;
-use futures_util::{future::FutureExt, lock::Mutex, pin_mut, select, StreamExt};
-use turbulence::{BufferPacket, Packet, PacketPool, Runtime as TRuntime};
+use futures_util::{
+    future::{Fuse, FutureExt},
+    pin_mut, select, StreamExt,
+};
+use turbulence::{
+    packet_multiplexer::IncomingTrySendError, BufferPacket, Packet, PacketPool, Runtime as TRuntime,
+};
 
 use crate::{
     buffer::SimpleBufferPool,
+    error::NetworkError,
+    event::{ConnectionEvent, EventPublisher},
     message::{
-        InternalMessage, Message, RawMessage, ReliableMessage, SignedMessage, MESSAGE_SETTINGS,
-        RELIABLE_MESSAGE_SETTINGS,
+        ControlMessage, InternalMessage, RawMessage, SignedMessage, CONTROL_MESSAGE_SETTINGS,
     },
-    runtime::{Runtime, RuntimeImpl},
+    protocol::Protocol,
+    runtime::{Runtime, RuntimeImpl, RuntimeInstant},
+    stats::{ConnectionStats, StatsTracker},
 };
 
 pub struct BidirectionalChannel<T> {
@@ -33,6 +45,12 @@ impl<T> BidirectionalChannel<T> {
     }
 }
 
+impl<T> Default for BidirectionalChannel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct DirectionalChannel<T> {
     receiver: async_channel::Receiver<T>,
     sender: async_channel::Sender<T>,
@@ -43,11 +61,46 @@ impl<T> DirectionalChannel<T> {
         let (sender, receiver) = async_channel::unbounded();
         DirectionalChannel::<T> { sender, receiver }
     }
+
+    pub fn bounded(capacity: usize) -> Self {
+        let (sender, receiver) = async_channel::bounded(capacity);
+        DirectionalChannel::<T> { sender, receiver }
+    }
+}
+
+impl<T> Default for DirectionalChannel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// How many messages or packets can be queued for a single connection before the
+// multiplexer starts rejecting them with `NetworkError::ChannelFull`.
+pub const CONNECTION_QUEUE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    // How often each side pings the other. The pings keep a quiet connection alive and
+    // are also where RTT and packet loss are sampled from.
+    pub keepalive_interval: Duration,
+    // A connection that hasn't heard anything from its peer for this long is dropped
+    // with `NetworkError::TimedOut`.
+    pub idle_timeout: Duration,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            keepalive_interval: Duration::from_secs(1),
+            idle_timeout: Duration::from_secs(10),
+        }
+    }
 }
 
-pub struct MessageProcessor<R>
+pub struct MessageProcessor<R, P>
 where
     R: Runtime,
+    P: Protocol,
 {
     id: usize,
     runtime: RuntimeImpl<R>,
@@ -57,64 +110,72 @@ where
     incoming_packets: turbulence::IncomingMultiplexedPackets<turbulence::BufferPacket<RawMessage>>,
     outgoing_packets: turbulence::OutgoingMultiplexedPackets<turbulence::BufferPacket<RawMessage>>,
 
-    reliable_message_channel: DirectionalChannel<ReliableMessage>,
-    message_channel: DirectionalChannel<Message>,
+    message_channel: DirectionalChannel<P::Message>,
+    // Messages turbulence had no room for yet, sent ahead of anything new.
+    pending_messages: VecDeque<P::Message>,
 
     byte_channel_outgoing: Sender<SignedMessage<RawMessage>>,
-    message_outgoing: Sender<SignedMessage<Message>>,
-    reliable_message_outgoing: Sender<SignedMessage<ReliableMessage>>,
+    message_outgoing: Sender<SignedMessage<P::Message>>,
     incoming_byte_channel: DirectionalChannel<RawMessage>,
     internal_channel: DirectionalChannel<InternalMessage>,
-}
 
-enum WrappedMessage {
-    Message(Message),
-    ReliableMessage(ReliableMessage),
+    config: ConnectionConfig,
+    events: EventPublisher,
+    connected: bool,
+    started: RuntimeInstant,
+    stats: StatsTracker,
+    last_ping: RuntimeInstant,
 }
-enum Action {
+
+enum Action<M> {
     EmitBytes(BufferPacket<Box<[u8]>>),
     DispatchBytes(RawMessage),
-    DispatchMessage(WrappedMessage),
-    EmitMessage(WrappedMessage),
-    Error,
+    DispatchMessage(M),
+    Error(NetworkError),
     Shutdown,
     Flush,
 }
 
-pub(crate) struct ChannelBundle {
-    pub message_sender: Sender<Message>,
+pub(crate) struct ChannelBundle<M> {
+    pub message_sender: Sender<M>,
     pub byte_sender: Sender<RawMessage>,
-    pub reliable_message_sender: Sender<ReliableMessage>,
     pub internal_sender: Sender<InternalMessage>,
+    pub stats: Arc<Mutex<ConnectionStats>>,
 }
 
-impl<R> MessageProcessor<R>
+impl<R, P> MessageProcessor<R, P>
 where
     R: Runtime + Clone + 'static,
+    P: Protocol,
 {
     fn new(
         id: usize,
         runtime: R,
         byte_channel_outgoing: Sender<SignedMessage<RawMessage>>,
-        reliable_message_outgoing: Sender<SignedMessage<ReliableMessage>>,
-        message_outgoing: Sender<SignedMessage<Message>>,
+        message_outgoing: Sender<SignedMessage<P::Message>>,
+        config: ConnectionConfig,
+        events: EventPublisher,
     ) -> Self {
-        let pool = turbulence::BufferPacketPool::new(SimpleBufferPool(32));
+        // turbulence assumes every packet can hold its largest message and panics when
+        // a message doesn't fit, so packets get the full size.
+        let pool = turbulence::BufferPacketPool::new(SimpleBufferPool(turbulence::MAX_PACKET_LEN));
         let runtime = RuntimeImpl::new(runtime);
         let mut multiplexer = turbulence::PacketMultiplexer::new();
         let mut builder = turbulence::MessageChannelsBuilder::new(runtime.clone(), pool);
-        builder.register::<Message>(MESSAGE_SETTINGS).unwrap();
+        P::register(&mut builder)
+            .expect("Protocol registered the same message type or channel twice");
         builder
-            .register::<ReliableMessage>(RELIABLE_MESSAGE_SETTINGS)
-            .unwrap();
+            .register::<ControlMessage>(CONTROL_MESSAGE_SETTINGS)
+            .expect("Protocol registered a message on the control channel");
 
         let turbulence_channels = builder.build(&mut multiplexer);
         let (incoming_packets, outgoing_packets) = multiplexer.start();
 
-        let message_channel = DirectionalChannel::<Message>::new();
-        let reliable_message_channel = DirectionalChannel::<ReliableMessage>::new();
-        let incoming_byte_channel = DirectionalChannel::<RawMessage>::new();
+        let message_channel = DirectionalChannel::<P::Message>::bounded(CONNECTION_QUEUE_SIZE);
+        let incoming_byte_channel =
+            DirectionalChannel::<RawMessage>::bounded(CONNECTION_QUEUE_SIZE);
         let internal_channel = DirectionalChannel::<InternalMessage>::new();
+        let now = runtime.now();
 
         MessageProcessor {
             id,
@@ -124,42 +185,66 @@ where
             incoming_packets,
             outgoing_packets,
 
-            reliable_message_channel,
             message_channel,
+            pending_messages: VecDeque::new(),
             byte_channel_outgoing,
             incoming_byte_channel,
-            reliable_message_outgoing,
             message_outgoing,
             internal_channel,
+
+            config,
+            events,
+            connected: false,
+            started: now,
+            stats: StatsTracker::new(now),
+            last_ping: now,
+        }
+    }
+
+    // Runs until the multiplexer shuts the connection down, or until something goes
+    // wrong with this connection in which case the reason is returned. Either way only
+    // this connection is affected.
+    pub async fn run(mut self) -> Result<(), NetworkError> {
+        let result = self.process().await;
+        if let Err(NetworkError::Decode(id, _)) = result {
+            let err = self.turbulence_channels.recv_err().await;
+            return Err(NetworkError::Decode(id, err.to_string()));
         }
+        result
     }
 
-    pub async fn run(mut self) {
+    async fn process(&mut self) -> Result<(), NetworkError> {
         loop {
+            // turbulence decodes incoming packets on its own tasks, so hand off whatever
+            // it has finished with before waiting on anything else.
+            while let Some(message) = P::recv(&mut self.turbulence_channels) {
+                self.mark_connected();
+                self.message_outgoing
+                    .send(SignedMessage::<P::Message> {
+                        id: self.id,
+                        message,
+                    })
+                    .await
+                    .map_err(|_| NetworkError::ConnectionClosed(self.id))?;
+            }
+            while let Some(message) = self.turbulence_channels.recv::<ControlMessage>() {
+                self.mark_connected();
+                self.process_control(message);
+            }
+            if !self.turbulence_channels.is_connected() {
+                return Err(NetworkError::Decode(self.id, String::new()));
+            }
+
             let action = {
-                let pending_outgoing_message = self.message_channel.receiver.recv().fuse();
+                // Stop taking new messages while turbulence is still catching up so
+                // the backlog pushes back on the multiplexer instead of growing here.
+                let pending_outgoing_message = if self.pending_messages.is_empty() {
+                    self.message_channel.receiver.recv().fuse()
+                } else {
+                    Fuse::terminated()
+                };
                 pin_mut!(pending_outgoing_message);
 
-                let pending_outgoing_reliable_message =
-                    self.reliable_message_channel.receiver.recv().fuse();
-                pin_mut!(pending_outgoing_reliable_message);
-
-                let channel_lock = Arc::new(Mutex::new(&mut self.turbulence_channels));
-
-                let pending_incoming_message = async {
-                    let mut lock = channel_lock.lock().await;
-                    lock.try_async_recv::<Message>().await
-                }
-                .fuse();
-                pin_mut!(pending_incoming_message);
-
-                let pending_incoming_reliable_message = async {
-                    let mut lock = channel_lock.lock().await;
-                    lock.try_async_recv::<ReliableMessage>().await
-                }
-                .fuse();
-                pin_mut!(pending_incoming_reliable_message);
-
                 let pending_incoming_byte = self.incoming_byte_channel.receiver.recv().fuse();
                 pin_mut!(pending_incoming_byte);
 
@@ -175,41 +260,24 @@ where
                     .fuse();
                 pin_mut!(sleep_timer);
 
-                let action: Action = select! {
-                    incoming = pending_incoming_message => {
-                        match incoming {
-                            Ok(message) => Action::EmitMessage(WrappedMessage::Message(message)),
-                            _ => Action::Error
-                        }
-                    },
-                    reliable_incoming = pending_incoming_reliable_message => {
-                        match reliable_incoming {
-                            Ok(message) => Action::EmitMessage(WrappedMessage::ReliableMessage(message)),
-                            _ => Action::Error
-                        }
-                    },
+                let closed = NetworkError::ConnectionClosed(self.id);
+                let action: Action<P::Message> = select! {
                     outgoing = pending_outgoing_message => {
                         match outgoing {
-                            Ok(message) => Action::DispatchMessage(WrappedMessage::Message(message)),
-                            _ => Action::Error
-                        }
-                    },
-                    reliable_outgoing = pending_outgoing_reliable_message => {
-                        match reliable_outgoing {
-                            Ok(message) => Action::DispatchMessage(WrappedMessage::ReliableMessage(message)),
-                            _ => Action::Error
+                            Ok(message) => Action::DispatchMessage(message),
+                            _ => Action::Error(closed)
                         }
                     },
                     incoming = pending_incoming_byte => {
                         match incoming {
                             Ok(bytes) => Action::DispatchBytes(bytes),
-                            _ => Action::Error
+                            _ => Action::Error(closed)
                         }
                     },
                     outgoing = pending_outgoing_byte => {
                         match outgoing {
                             Some(bytes) => Action::EmitBytes(bytes),
-                            _ => Action::Error
+                            _ => Action::Error(closed)
                         }
                     },
                     message = pending_internal => {
@@ -219,7 +287,7 @@ where
                                     InternalMessage::Shutdown => Action::Shutdown
                                 }
                             },
-                            _ => Action::Error
+                            _ => Action::Error(closed)
                         }
                     },
                     _ = sleep_timer => {
@@ -228,16 +296,24 @@ where
                 };
                 action
             };
-            let mut breakout = false;
             match action {
                 Action::DispatchBytes(message) => {
+                    self.stats
+                        .record_incoming(self.runtime.now(), message.len());
                     let mut packet = self.pool.acquire();
                     packet.extend(&message);
-                    if let Err(err) = self.incoming_packets.try_send(packet) {
-                        tracing::info!("Can't keep up with incoming messages!");
+                    match self.incoming_packets.try_send(packet) {
+                        Ok(()) => {}
+                        Err(IncomingTrySendError::IsFull(_)) => {
+                            tracing::info!("Can't keep up with incoming messages!");
+                        }
+                        Err(IncomingTrySendError::Error(err)) => {
+                            tracing::warn!("Dropping packet on connection {}: {}", self.id, err);
+                        }
                     }
                 }
                 Action::EmitBytes(buf_bytes) => {
+                    self.stats.record_outgoing(buf_bytes.len());
                     let mut bytes = Vec::with_capacity(buf_bytes.len());
                     bytes.extend_from_slice(buf_bytes.as_slice());
                     self.byte_channel_outgoing
@@ -246,100 +322,152 @@ where
                             message: bytes.into_boxed_slice(),
                         })
                         .await
-                        .unwrap();
+                        .map_err(|_| NetworkError::ConnectionClosed(self.id))?;
                 }
-                Action::DispatchMessage(message) => match message {
-                    WrappedMessage::Message(message) => {
-                        self.turbulence_channels.send(message);
-                    }
-                    WrappedMessage::ReliableMessage(message) => {
-                        self.turbulence_channels.send(message);
-                    }
-                },
-                Action::EmitMessage(message) => match message {
-                    WrappedMessage::Message(message) => {
-                        self.message_outgoing
-                            .send(SignedMessage::<Message> {
-                                id: self.id,
-                                message,
-                            })
-                            .await
-                            .unwrap();
-                    }
-                    WrappedMessage::ReliableMessage(message) => {
-                        self.reliable_message_outgoing
-                            .send(SignedMessage::<ReliableMessage> {
-                                id: self.id,
-                                message,
-                            })
-                            .await
-                            .unwrap();
-                    }
-                },
-                Action::Error => {
-                    panic!("Error in the message processor.");
+                Action::DispatchMessage(message) => {
+                    self.pending_messages.push_back(message);
+                    self.dispatch_pending();
+                }
+                Action::Error(err) => {
+                    return Err(err);
                 }
                 Action::Flush => {
-                    self.turbulence_channels.flush::<ReliableMessage>();
-                    self.turbulence_channels.flush::<Message>();
+                    if self.is_idle() {
+                        return Err(NetworkError::TimedOut(self.id));
+                    }
+                    self.ping();
+                    P::flush(&mut self.turbulence_channels);
+                    self.turbulence_channels.flush::<ControlMessage>();
+                    self.dispatch_pending();
+                    self.stats.refresh(
+                        self.runtime.now(),
+                        self.message_channel.receiver.len() + self.pending_messages.len(),
+                        self.incoming_byte_channel.receiver.len(),
+                    );
                 }
                 Action::Shutdown => {
                     tracing::info!("Killing message processor");
-                    breakout = true;
+                    return Ok(());
                 }
             }
-            if breakout {
+        }
+    }
+
+    fn mark_connected(&mut self) {
+        if !self.connected {
+            self.connected = true;
+            self.events.publish(ConnectionEvent::Connected(self.id));
+        }
+    }
+
+    fn process_control(&mut self, message: ControlMessage) {
+        match message {
+            ControlMessage::Ping(id) => {
+                let pong = ControlMessage::Pong(id, self.stats.packets_in());
+                self.turbulence_channels.send(pong);
+            }
+            ControlMessage::Pong(id, peer_packets_in) => {
+                self.stats
+                    .finish_ping(self.runtime.now(), id, peer_packets_in);
+            }
+        }
+    }
+
+    fn ping(&mut self) {
+        let now = self.runtime.now();
+        if self.runtime.duration_between(self.last_ping, now) < self.config.keepalive_interval {
+            return;
+        }
+        self.last_ping = now;
+        let id = self.stats.start_ping(now);
+        self.turbulence_channels.send(ControlMessage::Ping(id));
+    }
+
+    // Connections that never heard from their peer are timed from when they started.
+    fn is_idle(&self) -> bool {
+        let last_heard = self.stats.last_heard().unwrap_or(self.started);
+        self.runtime
+            .duration_between(last_heard, self.runtime.now())
+            >= self.config.idle_timeout
+    }
+
+    fn dispatch_pending(&mut self) {
+        while let Some(message) = self.pending_messages.pop_front() {
+            if let Some(message) = P::send(&mut self.turbulence_channels, message) {
+                self.pending_messages.push_front(message);
+                P::flush(&mut self.turbulence_channels);
                 break;
             }
         }
     }
 
-    pub(crate) fn channel_bundle(&self) -> ChannelBundle {
+    pub(crate) fn channel_bundle(&self) -> ChannelBundle<P::Message> {
         ChannelBundle {
             message_sender: self.message_channel.sender.clone(),
-            reliable_message_sender: self.reliable_message_channel.sender.clone(),
             byte_sender: self.incoming_byte_channel.sender.clone(),
             internal_sender: self.internal_channel.sender.clone(),
+            stats: self.stats.shared(),
         }
     }
 }
 
-#[derive(Clone)]
-pub struct MessageProcessorFactory<R>
+pub struct MessageProcessorFactory<R, P>
 where
     R: Runtime + 'static,
+    P: Protocol,
 {
     runtime: R,
     byte_channel_outgoing: Sender<SignedMessage<RawMessage>>,
-    message_channel_outgoing: Sender<SignedMessage<Message>>,
-    reliable_channel_outgoing: Sender<SignedMessage<ReliableMessage>>,
+    message_channel_outgoing: Sender<SignedMessage<P::Message>>,
+    config: ConnectionConfig,
+    events: EventPublisher,
+}
+
+impl<R, P> Clone for MessageProcessorFactory<R, P>
+where
+    R: Runtime + 'static,
+    P: Protocol,
+{
+    fn clone(&self) -> Self {
+        Self {
+            runtime: self.runtime.clone(),
+            byte_channel_outgoing: self.byte_channel_outgoing.clone(),
+            message_channel_outgoing: self.message_channel_outgoing.clone(),
+            config: self.config.clone(),
+            events: self.events.clone(),
+        }
+    }
 }
 
-impl<R> MessageProcessorFactory<R>
+impl<R, P> MessageProcessorFactory<R, P>
 where
     R: Runtime,
+    P: Protocol,
 {
-    pub fn new(
+    pub(crate) fn new(
         runtime: R,
         byte_channel_outgoing: Sender<SignedMessage<RawMessage>>,
-        message_channel_outgoing: Sender<SignedMessage<Message>>,
-        reliable_channel_outgoing: Sender<SignedMessage<ReliableMessage>>,
+        message_channel_outgoing: Sender<SignedMessage<P::Message>>,
+        config: ConnectionConfig,
+        events: EventPublisher,
     ) -> Self {
         Self {
             byte_channel_outgoing,
             runtime,
             message_channel_outgoing,
-            reliable_channel_outgoing,
+            config,
+            events,
         }
     }
 
-    pub fn build(&self, id: usize) -> MessageProcessor<R> {
+    pub fn build(&self, id: usize) -> MessageProcessor<R, P> {
         MessageProcessor::new(
             id,
             self.runtime.clone(),
             self.byte_channel_outgoing.clone(),
-            self.reliable_channel_outgoing.clone(),
             self.message_channel_outgoing.clone(),
+            self.config.clone(),
+            self.events.clone(),
         )
     }
 }
@@ -0,0 +1,32 @@
+use std::fmt::Debug;
+
+use turbulence::{
+    message_channels::ChannelAlreadyRegistered, MessageChannels, MessageChannelsBuilder, PacketPool,
+};
+
+// A protocol is the set of message types an application sends over the wire.
+// Every message type gets its own turbulence channel, and the protocol wraps all of
+// them up in a single `Message` type (usually an enum with one variant per type)
+// so the multiplexer and processors only ever have to move one thing around.
+pub trait Protocol: Send + Sync + 'static {
+    type Message: Debug + Clone + Send + Sync + 'static;
+
+    // Registers every message type along with its channel settings.
+    fn register<R, P>(
+        builder: &mut MessageChannelsBuilder<R, P>,
+    ) -> Result<(), ChannelAlreadyRegistered>
+    where
+        R: turbulence::Runtime + 'static,
+        P: PacketPool + Clone + Send + 'static,
+        P::Packet: Unpin + Send;
+
+    // Queues a message on the channel matching its type. Hands the message back if
+    // the channel is full.
+    fn send(channels: &mut MessageChannels, message: Self::Message) -> Option<Self::Message>;
+
+    // Returns the next decoded message from any of the registered channels.
+    fn recv(channels: &mut MessageChannels) -> Option<Self::Message>;
+
+    // Flushes every registered channel.
+    fn flush(channels: &mut MessageChannels);
+}
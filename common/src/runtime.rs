@@ -6,6 +6,12 @@ use std::future::Future;
 #[derive(Copy, Clone)]
 pub struct RuntimeInstant(instant::Instant);
 
+impl From<instant::Instant> for RuntimeInstant {
+    fn from(instant: instant::Instant) -> Self {
+        RuntimeInstant(instant)
+    }
+}
+
 pub struct RuntimeInstantHandler {}
 
 impl RuntimeInstantHandler {
@@ -30,6 +36,41 @@ pub trait Runtime: Clone + Send + Sync  {
         F: std::future::Future<Output = ()> + Send + 'static;
 
     fn sleep(&self, duration: std::time::Duration) -> Self::Sleep;
+
+    // Runtimes that keep their own clock (see `VirtualRuntime`) override this so
+    // turbulence's timers follow it instead of the wall clock.
+    fn now(&self) -> RuntimeInstant {
+        RuntimeInstantHandler::now()
+    }
+}
+
+// Spawns onto and sleeps on whatever tokio runtime the caller is running in, for the
+// server and native clients.
+#[cfg(feature = "tokio")]
+#[derive(Clone, Default)]
+pub struct NativeRuntime {}
+
+#[cfg(feature = "tokio")]
+impl NativeRuntime {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Runtime for NativeRuntime {
+    type Sleep = tokio::time::Sleep;
+
+    fn spawn<F>(&self, future: F)
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        tokio::spawn(future);
+    }
+
+    fn sleep(&self, duration: std::time::Duration) -> Self::Sleep {
+        tokio::time::sleep(duration)
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -66,11 +107,11 @@ where
     }
 
     fn now(&self) -> Self::Instant {
-        RuntimeInstantHandler::now()
+        self.runtime.now()
     }
 
     fn elapsed(&self, instant: Self::Instant) -> instant::Duration {
-        RuntimeInstantHandler::elapsed(instant)
+        RuntimeInstantHandler::duration_between(instant, self.runtime.now())
     }
 
     fn duration_between(&self, earlier: Self::Instant, later: Self::Instant) -> instant::Duration {
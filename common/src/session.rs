@@ -0,0 +1,439 @@
+use crate::{
+    clock::{Clock, ClockSync},
+    message::{GameMessage, Message, Object, ReliableMessage},
+    movement::{PlayerMovement, Prediction},
+    simulation::SPAWN_POSITION,
+    snapshot::{SnapshotReceiver, StateChanges, WorldState},
+    stats::TickStats,
+};
+
+// Something the session did that its owner may want to act on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+    // A snapshot was applied and is now `state()`.
+    Snapshot {
+        tick: u64,
+        // Server clock when the tick was simulated, in milliseconds.
+        time: f64,
+    },
+    // The server put us in the room with this code, the old world is gone.
+    JoinedRoom(String),
+    // How the room's recent ticks went.
+    TickStats(TickStats),
+}
+
+// The client side of a game session: the world as the server last described it, our
+// predicted player and the texts we were sent. Like `GameHost` it never touches the
+// network, whoever owns it feeds it messages and drains `outgoing`, so the browser
+// and native clients run the same logic over different connections.
+pub struct ClientSession {
+    state: WorldState,
+    // What happened to `state` since the owner last asked.
+    changes: StateChanges,
+    room: Option<String>,
+    // Epoch of the room we're in. Snapshots and acks from any other are stragglers from
+    // a room we left and are dropped.
+    epoch: Option<u32>,
+    pending_messages: Vec<String>,
+
+    clock: Clock,
+    server_clock: ClockSync,
+    snapshots: SnapshotReceiver,
+
+    prediction: Prediction,
+    // Id of our player's object in the world state, known once the server acks input.
+    player_object: Option<u32>,
+    // Where the player clicked since the last `update`.
+    pending_target: Option<(f32, f32)>,
+
+    outgoing: Vec<GameMessage>,
+}
+
+impl Default for ClientSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClientSession {
+    pub fn new() -> Self {
+        Self {
+            state: WorldState::new(),
+            changes: StateChanges::default(),
+            room: None,
+            epoch: None,
+            pending_messages: Vec::new(),
+            clock: Clock::new(),
+            server_clock: ClockSync::new(),
+            snapshots: SnapshotReceiver::new(),
+            prediction: Prediction::new(PlayerMovement::new(SPAWN_POSITION)),
+            player_object: None,
+            pending_target: None,
+            outgoing: Vec::new(),
+        }
+    }
+
+    // Messages for the server produced since the last call, in the order they should
+    // be sent.
+    pub fn outgoing(&mut self) -> Vec<GameMessage> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    // Forgets everything about the world we were in. A new server session or room has
+    // its own clock, ticks and objects.
+    pub fn reset(&mut self) {
+        self.server_clock = ClockSync::new();
+        self.snapshots = SnapshotReceiver::new();
+        self.prediction = Prediction::new(PlayerMovement::new(SPAWN_POSITION));
+        self.player_object = None;
+        self.epoch = None;
+        let state = std::mem::take(&mut self.state);
+        self.changes.record(&state, &self.state);
+    }
+
+    // The connection is gone, so whatever room we were in is too.
+    pub fn disconnected(&mut self) {
+        self.room = None;
+    }
+
+    pub fn handle_message(&mut self, message: GameMessage) -> Option<SessionEvent> {
+        match message {
+            GameMessage::Unreliable(message) => self.handle_unreliable(message),
+            GameMessage::Reliable(message) => self.handle_reliable(message),
+        }
+    }
+
+    fn handle_unreliable(&mut self, message: Message) -> Option<SessionEvent> {
+        match message {
+            Message::Sync(origin) => {
+                let received = self.clock.now();
+                self.send(Message::SyncReply(origin, received, self.clock.now()));
+            }
+            Message::SyncReply(origin, received, transmit) => {
+                self.server_clock
+                    .record(origin, received, transmit, self.clock.now());
+            }
+            Message::State(object) => self.upsert(object),
+            Message::Delta(delta) => {
+                if !self.in_epoch(delta.epoch) {
+                    return None;
+                }
+                let time = delta.time;
+                let tick = self.snapshots.apply(delta)?;
+                return Some(self.applied(tick, time));
+            }
+            Message::InputAck(epoch, ack) => {
+                if self.in_epoch(epoch) {
+                    self.player_object = Some(ack.object);
+                    self.prediction.reconcile(&ack);
+                }
+            }
+            Message::TickStats(stats) => {
+                if self.in_epoch(stats.epoch) {
+                    return Some(SessionEvent::TickStats(stats));
+                }
+            }
+            Message::Position(_, _)
+            | Message::Player(_, _)
+            | Message::Ack(_, _)
+            | Message::Input(_, _)
+            | Message::Unknown => {}
+        }
+        None
+    }
+
+    fn handle_reliable(&mut self, message: ReliableMessage) -> Option<SessionEvent> {
+        match message {
+            ReliableMessage::State(objects) => {
+                for (_, object) in objects {
+                    self.upsert(object);
+                }
+            }
+            ReliableMessage::Text(text) => {
+                tracing::info!("Received message: {:?}", text);
+                self.pending_messages.push(text);
+            }
+            // Snapshots bring the same changes, these just let us react before one arrives.
+            ReliableMessage::Spawn(objects) => {
+                for object in objects {
+                    self.upsert(object);
+                }
+            }
+            ReliableMessage::Despawn(ids) => {
+                for id in ids {
+                    if self.state.remove(&id).is_some() {
+                        self.changes.mark_removed(id);
+                    }
+                }
+            }
+            ReliableMessage::JoinedRoom(room, epoch) => {
+                tracing::info!("Joined room {}", room);
+                self.pending_messages.push(format!("Joined room {}", room));
+                self.room = Some(room.clone());
+                // We may have heard from the room already and built up its world.
+                if self.epoch == Some(epoch) {
+                    return None;
+                }
+                self.reset();
+                self.epoch = Some(epoch);
+                return Some(SessionEvent::JoinedRoom(room));
+            }
+            ReliableMessage::Snapshot(part) => {
+                if !self.in_epoch(part.delta.epoch) {
+                    return None;
+                }
+                let time = part.delta.time;
+                let tick = self.snapshots.apply_part(part)?;
+                return Some(self.applied(tick, time));
+            }
+            ReliableMessage::Connected(client) => {
+                tracing::info!("Connected: {:?}", client);
+            }
+            ReliableMessage::Disconnected(client) => {
+                tracing::info!("Disconnected: {:?}", client);
+            }
+            ReliableMessage::Connect | ReliableMessage::JoinRoom(_) => {}
+        }
+        None
+    }
+
+    // Whether a message from the room with `epoch` is for the room we're in. Until the
+    // server says which room that is, the first one we hear from is it.
+    fn in_epoch(&mut self, epoch: u32) -> bool {
+        *self.epoch.get_or_insert(epoch) == epoch
+    }
+
+    // Takes on the snapshot that was just applied and lets the server know we have it.
+    fn applied(&mut self, tick: u64, time: f64) -> SessionEvent {
+        if let Some(latest) = self.snapshots.latest() {
+            self.changes.record(&self.state, latest);
+            self.state = latest.clone();
+        }
+        let epoch = self.epoch.unwrap_or_default();
+        self.send(Message::Ack(epoch, tick));
+        SessionEvent::Snapshot { tick, time }
+    }
+
+    fn upsert(&mut self, object: Object) {
+        match self.state.get(&object.id) {
+            None => self.changes.mark_added(object.id),
+            Some(previous) if *previous != object => self.changes.mark_changed(object.id),
+            Some(_) => {}
+        }
+        self.state.insert(object.id, object);
+    }
+
+    // Samples input for a frame that took `dt` milliseconds, applies it locally and
+    // queues it for the server.
+    pub fn update(&mut self, dt: f32) {
+        let inputs = self.prediction.input(self.pending_target.take(), dt);
+        let epoch = self.epoch.unwrap_or_default();
+        self.send(Message::Input(epoch, inputs));
+    }
+
+    // Walks towards `(x, y)` from the next `update` on.
+    pub fn click(&mut self, x: f32, y: f32) {
+        self.pending_target = Some((x, y));
+        self.send(Message::Position(x, y));
+    }
+
+    pub fn send_text(&mut self, text: String) {
+        self.send(ReliableMessage::Text(text));
+    }
+
+    // Asks for the room with code `room`, or for a match without one. The server holds
+    // off on putting us anywhere until we do.
+    pub fn request_room(&mut self, room: Option<String>) {
+        self.send(ReliableMessage::JoinRoom(room));
+    }
+
+    // The latest world state from the server.
+    pub fn state(&self) -> &WorldState {
+        &self.state
+    }
+
+    // Our own player is drawn where prediction puts it rather than where the server
+    // last saw it.
+    pub fn predicted(&self, mut state: WorldState) -> WorldState {
+        if let Some(object) = self.player_object.and_then(|id| state.get_mut(&id)) {
+            object.set_position(self.prediction.movement().position);
+        }
+        state
+    }
+
+    // Objects that were added, changed or removed since the last call.
+    pub fn take_changes(&mut self) -> StateChanges {
+        self.changes.take()
+    }
+
+    // Texts received since the last call.
+    pub fn take_messages(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.pending_messages)
+    }
+
+    // Code of the room we're in, once the server has put us in one.
+    pub fn room(&self) -> Option<&str> {
+        self.room.as_deref()
+    }
+
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    // The server's clock in milliseconds, once the first sync finished.
+    pub fn server_time(&self) -> Option<f64> {
+        self.server_clock.remote_time(self.clock.now())
+    }
+
+    fn send<M: Into<GameMessage>>(&mut self, message: M) {
+        self.outgoing.push(message.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::movement::InputAck;
+    use crate::snapshot::{diff, SnapshotDelta};
+
+    fn object(id: u32, position: (f32, f32)) -> Object {
+        let mut object = Object::new(id);
+        object.set_position(position);
+        object
+    }
+
+    fn full(epoch: u32, tick: u64, objects: &[Object]) -> GameMessage {
+        let state: WorldState = objects.iter().map(|o| (o.id, o.clone())).collect();
+        let (changed, removed) = diff(None, &state);
+        Message::Delta(SnapshotDelta {
+            epoch,
+            tick,
+            time: tick as f64 * 50.0,
+            baseline: None,
+            changed,
+            removed,
+        })
+        .into()
+    }
+
+    fn joined(code: &str, epoch: u32) -> GameMessage {
+        ReliableMessage::JoinedRoom(code.to_string(), epoch).into()
+    }
+
+    fn input_ack(epoch: u32, object: u32, position: (f32, f32)) -> GameMessage {
+        Message::InputAck(
+            epoch,
+            InputAck {
+                object,
+                sequence: 0,
+                movement: PlayerMovement::new(position),
+            },
+        )
+        .into()
+    }
+
+    #[test]
+    fn snapshots_are_applied_and_acked() {
+        let mut session = ClientSession::new();
+        let event = session.handle_message(full(5, 3, &[object(1, (2.0, 3.0))]));
+        assert_eq!(
+            event,
+            Some(SessionEvent::Snapshot {
+                tick: 3,
+                time: 150.0
+            })
+        );
+        assert_eq!(session.state()[&1].position(), Some((2.0, 3.0)));
+        assert!(session.take_changes().added.contains(&1));
+        assert!(matches!(
+            session.outgoing().as_slice(),
+            [GameMessage::Unreliable(Message::Ack(5, 3))]
+        ));
+
+        // Stale snapshots change nothing and aren't acked again.
+        assert_eq!(session.handle_message(full(5, 2, &[])), None);
+        assert!(session.outgoing().is_empty());
+        assert!(session.state().contains_key(&1));
+    }
+
+    #[test]
+    fn joining_a_room_forgets_the_old_world() {
+        let mut session = ClientSession::new();
+        session.handle_message(joined("abc", 1));
+        session.handle_message(full(1, 3, &[object(1, (2.0, 3.0))]));
+        session.take_changes();
+
+        let event = session.handle_message(joined("def", 2));
+        assert_eq!(event, Some(SessionEvent::JoinedRoom("def".to_string())));
+        assert_eq!(session.room(), Some("def"));
+        assert!(session.state().is_empty());
+        assert!(session.take_changes().removed.contains(&1));
+        let messages = vec!["Joined room abc".to_string(), "Joined room def".to_string()];
+        assert_eq!(session.take_messages(), messages);
+
+        // The new room counts its ticks from scratch.
+        session.handle_message(full(2, 1, &[object(7, (0.0, 0.0))]));
+        assert!(session.state().contains_key(&7));
+    }
+
+    #[test]
+    fn stragglers_from_the_old_room_are_dropped() {
+        let mut session = ClientSession::new();
+        session.handle_message(joined("abc", 1));
+        session.handle_message(joined("def", 2));
+        session.outgoing();
+
+        // A late snapshot from the old room would otherwise hold back every new one.
+        assert_eq!(
+            session.handle_message(full(1, 500, &[object(1, (2.0, 3.0))])),
+            None
+        );
+        assert!(session.outgoing().is_empty());
+        session.handle_message(input_ack(1, 1, (2.0, 3.0)));
+        let stats = |epoch| TickStats {
+            epoch,
+            ticks: 20,
+            ..TickStats::default()
+        };
+        assert_eq!(
+            session.handle_message(Message::TickStats(stats(1)).into()),
+            None
+        );
+        assert_eq!(
+            session.handle_message(Message::TickStats(stats(2)).into()),
+            Some(SessionEvent::TickStats(stats(2)))
+        );
+
+        session.handle_message(full(2, 1, &[object(7, (0.0, 0.0))]));
+        assert!(matches!(
+            session.outgoing().as_slice(),
+            [GameMessage::Unreliable(Message::Ack(2, 1))]
+        ));
+        assert_eq!(session.state().keys().collect::<Vec<_>>(), vec![&7]);
+        let predicted = session.predicted(session.state().clone());
+        assert_eq!(predicted[&7].position(), Some((0.0, 0.0)));
+    }
+
+    #[test]
+    fn a_late_joined_room_keeps_what_the_room_already_sent() {
+        let mut session = ClientSession::new();
+        session.handle_message(full(4, 1, &[object(7, (0.0, 0.0))]));
+        assert_eq!(session.handle_message(joined("abc", 4)), None);
+        assert_eq!(session.room(), Some("abc"));
+        assert!(session.state().contains_key(&7));
+    }
+
+    #[test]
+    fn our_player_is_drawn_where_prediction_puts_it() {
+        let mut session = ClientSession::new();
+        session.handle_message(full(0, 1, &[object(4, SPAWN_POSITION)]));
+        session.handle_message(input_ack(0, 4, SPAWN_POSITION));
+        session.click(SPAWN_POSITION.0 + 100.0, SPAWN_POSITION.1);
+        session.update(100.0);
+
+        let predicted = session.predicted(session.state().clone());
+        let expected = (SPAWN_POSITION.0 + 10.0, SPAWN_POSITION.1);
+        assert_eq!(predicted[&4].position(), Some(expected));
+        assert_eq!(session.state()[&4].position(), Some(SPAWN_POSITION));
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+
+use rand::Rng;
+
+use crate::{
+    component::{Component, PlayerInfo, Renderable, Shape, Transform},
+    message::Object,
+    movement::{InputAck, InputCommand, PlayerMovement, MAX_INPUT_DT},
+    snapshot::WorldState,
+    spatial::SpatialGrid,
+};
+
+// Size of the playing field trees are scattered over.
+pub const WORLD_SIZE: (f32, f32) = (500.0, 400.0);
+pub const SPAWN_POSITION: (f32, f32) = (0.0, 0.0);
+const TREE_COLOR: &str = "#288b22";
+const PLAYER_SIZE: f32 = 5.0;
+// Most input time, in milliseconds, a player can have banked. Inputs bunch up behind
+// network jitter and get applied a few ticks' worth at a time, this lets them catch up
+// without letting a client claim more time than the server has simulated.
+const MAX_INPUT_BUDGET: f32 = 250.0;
+
+struct PlayerState {
+    // Id of the player's object in the world state.
+    object: u32,
+    movement: PlayerMovement,
+    last_input: u32,
+    // Milliseconds of input the player may still spend. Every tick adds the tick
+    // length, every applied input takes its dt.
+    budget: f32,
+}
+
+// The game rules with no I/O or clocks attached. Whoever owns it decides when inputs
+// arrive and when ticks happen, so the server, an offline client or a test can all
+// drive the same world.
+pub struct Simulation {
+    state: WorldState,
+    // Positions of everything in `state`, kept in step with it.
+    index: SpatialGrid,
+    players: HashMap<u32, PlayerState>,
+    next_object_id: u32,
+    tick: u64,
+    // Length of a tick in milliseconds.
+    ms_per_tick: f32,
+}
+
+impl Simulation {
+    pub fn new(ms_per_tick: f32) -> Self {
+        Self {
+            state: WorldState::new(),
+            index: SpatialGrid::default(),
+            players: HashMap::new(),
+            next_object_id: 0,
+            tick: 0,
+            ms_per_tick,
+        }
+    }
+
+    pub fn spawn_trees<G: Rng>(&mut self, count: usize, rng: &mut G) {
+        for _ in 0..count {
+            let position = (
+                rng.gen_range(0.0, WORLD_SIZE.0),
+                rng.gen_range(0.0, WORLD_SIZE.1),
+            );
+            let renderable = Renderable {
+                shape: Shape::Tree,
+                size: rng.gen_range(3.0, 10.0),
+                color: TREE_COLOR.to_string(),
+            };
+            self.spawn(|object| object.with(Transform { position }).with(renderable));
+        }
+    }
+
+    // Adds a player and returns the id of its object.
+    pub fn add_player(&mut self, player: u32, color: String) -> u32 {
+        let renderable = Renderable {
+            shape: Shape::Player,
+            size: PLAYER_SIZE,
+            color,
+        };
+        let object = self.spawn(|object| {
+            object
+                .with(Transform {
+                    position: SPAWN_POSITION,
+                })
+                .with(renderable)
+                .with(PlayerInfo { player })
+        });
+        self.players.insert(
+            player,
+            PlayerState {
+                object,
+                movement: PlayerMovement::new(SPAWN_POSITION),
+                last_input: 0,
+                budget: MAX_INPUT_BUDGET,
+            },
+        );
+        object
+    }
+
+    pub fn remove_player(&mut self, player: u32) {
+        if let Some(removed) = self.players.remove(&player) {
+            self.state.remove(&removed.object);
+            self.index.remove(removed.object);
+        }
+    }
+
+    // Inputs that were already applied are ignored, clients resend them until acked.
+    // Inputs claiming more time than the player has left in its budget are cut short,
+    // the client finds out from the next ack and snaps back.
+    pub fn apply_input(&mut self, player: u32, input: &InputCommand) {
+        let player = match self.players.get_mut(&player) {
+            Some(player) => player,
+            None => return,
+        };
+        if input.sequence <= player.last_input {
+            return;
+        }
+        let dt = if input.dt.is_finite() {
+            input.dt.clamp(0.0, MAX_INPUT_DT).min(player.budget)
+        } else {
+            0.0
+        };
+        player.budget -= dt;
+        player.movement.apply(&InputCommand { dt, ..*input });
+        player.last_input = input.sequence;
+        if let Some(object) = self.state.get_mut(&player.object) {
+            object.set_position(player.movement.position);
+            self.index.insert(player.object, player.movement.position);
+        }
+    }
+
+    // Advances the world by one tick. Players only move through their inputs, so for
+    // now this just moves the tick counter along and gives every player another tick
+    // of input time.
+    pub fn step(&mut self) {
+        self.tick += 1;
+        for player in self.players.values_mut() {
+            player.budget = (player.budget + self.ms_per_tick).min(MAX_INPUT_BUDGET);
+        }
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn snapshot(&self) -> &WorldState {
+        &self.state
+    }
+
+    pub fn index(&self) -> &SpatialGrid {
+        &self.index
+    }
+
+    pub fn player_position(&self, player: u32) -> Option<(f32, f32)> {
+        self.players
+            .get(&player)
+            .map(|state| state.movement.position)
+    }
+
+    // The last input applied for `player` and where it left them.
+    pub fn input_ack(&self, player: u32) -> Option<InputAck> {
+        self.players.get(&player).map(|state| InputAck {
+            object: state.object,
+            sequence: state.last_input,
+            movement: state.movement,
+        })
+    }
+
+    // Every object that has a `C`, with it decoded.
+    pub fn query<C: Component>(&self) -> impl Iterator<Item = (u32, C)> + '_ {
+        self.state
+            .values()
+            .filter_map(|object| object.get::<C>().map(|component| (object.id, component)))
+    }
+
+    fn spawn<F: FnOnce(Object) -> Object>(&mut self, build: F) -> u32 {
+        let id = self.next_object_id;
+        self.next_object_id += 1;
+        let object = build(Object::new(id));
+        if let Some(position) = object.position() {
+            self.index.insert(id, position);
+        }
+        self.state.insert(id, object);
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::movement::PLAYER_SPEED;
+
+    const MS_PER_TICK: f32 = 50.0;
+
+    fn walk(sequence: u32, dt: f32) -> InputCommand {
+        InputCommand {
+            sequence,
+            target: Some((10_000.0, 0.0)),
+            dt,
+        }
+    }
+
+    fn distance(simulation: &Simulation) -> f32 {
+        simulation.player_position(1).unwrap().0 - SPAWN_POSITION.0
+    }
+
+    #[test]
+    fn a_burst_of_inputs_cannot_outrun_the_budget() {
+        let mut simulation = Simulation::new(MS_PER_TICK);
+        simulation.add_player(1, "red".to_string());
+        for sequence in 1..=50 {
+            simulation.apply_input(1, &walk(sequence, MAX_INPUT_DT));
+        }
+        let banked = PLAYER_SPEED * MAX_INPUT_BUDGET / 1000.0;
+        assert!((distance(&simulation) - banked).abs() < 0.01);
+
+        // Each tick only buys one more tick of movement.
+        simulation.step();
+        for sequence in 51..=100 {
+            simulation.apply_input(1, &walk(sequence, MAX_INPUT_DT));
+        }
+        let per_tick = PLAYER_SPEED * MS_PER_TICK / 1000.0;
+        assert!((distance(&simulation) - banked - per_tick).abs() < 0.01);
+    }
+
+    #[test]
+    fn inputs_that_keep_pace_with_ticks_are_untouched() {
+        let mut simulation = Simulation::new(MS_PER_TICK);
+        simulation.add_player(1, "red".to_string());
+        for sequence in 1..=100 {
+            simulation.apply_input(1, &walk(sequence, MS_PER_TICK));
+            simulation.step();
+        }
+        let expected = PLAYER_SPEED * MS_PER_TICK * 100.0 / 1000.0;
+        assert!((distance(&simulation) - expected).abs() < 0.01);
+    }
+
+    #[test]
+    fn idle_time_does_not_bank_past_the_cap() {
+        let mut simulation = Simulation::new(MS_PER_TICK);
+        simulation.add_player(1, "red".to_string());
+        for _ in 0..100 {
+            simulation.step();
+        }
+        for sequence in 1..=50 {
+            simulation.apply_input(1, &walk(sequence, MAX_INPUT_DT));
+        }
+        let banked = PLAYER_SPEED * MAX_INPUT_BUDGET / 1000.0;
+        assert!((distance(&simulation) - banked).abs() < 0.01);
+    }
+}
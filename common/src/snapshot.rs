@@ -0,0 +1,518 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+use turbulence::unreliable_channel;
+
+use crate::{component::ComponentData, message::Object};
+
+pub type WorldState = HashMap<u32, Object>;
+
+// How many past ticks either side keeps around to compute or apply deltas against.
+// A client that hasn't acknowledged anything this recent gets the whole world again.
+const HISTORY_LEN: usize = 64;
+// Most bytes of object changes that go into one `SnapshotPart`, well under what the
+// reliable channel takes in a single message.
+const SNAPSHOT_PART_LEN: u64 = 16 * 1024;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct SnapshotDelta {
+    // Epoch of the room the snapshot comes from. Ticks only count up within a room.
+    pub epoch: u32,
+    pub tick: u64,
+    // Server clock when the tick was simulated, in milliseconds.
+    pub time: f64,
+    // Tick the delta was computed against, `None` when it holds the whole world.
+    pub baseline: Option<u64>,
+    pub changed: Vec<ObjectChange>,
+    pub removed: Vec<u32>,
+}
+
+// A piece of a whole-world snapshot. Those can be far bigger than an unreliable
+// message may be, so they go over the reliable channel instead, split up so no one
+// message gets too big for it either.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct SnapshotPart {
+    pub delta: SnapshotDelta,
+    // Whether this is the final piece of `delta.tick`.
+    pub last: bool,
+}
+
+// Whether `delta` can go out as a single unreliable message.
+pub fn fits_unreliable(delta: &SnapshotDelta) -> bool {
+    // Bincode's default size is never smaller than what the channel encodes, and the
+    // message wrapped around the delta takes a few bytes more.
+    let limit = unreliable_channel::MAX_MESSAGE_LEN as u64;
+    matches!(bincode::serialized_size(delta), Ok(len) if len + 8 <= limit)
+}
+
+// Cuts `delta` into parts of at most about `SNAPSHOT_PART_LEN` bytes each, in the order
+// they have to be applied in.
+pub fn split(delta: SnapshotDelta) -> Vec<SnapshotPart> {
+    let SnapshotDelta {
+        epoch,
+        tick,
+        time,
+        baseline,
+        changed,
+        removed,
+    } = delta;
+    let empty = |removed| SnapshotDelta {
+        epoch,
+        tick,
+        time,
+        baseline,
+        changed: Vec::new(),
+        removed,
+    };
+    let mut parts = vec![empty(removed)];
+    let mut len = 0;
+    for change in changed {
+        let change_len = bincode::serialized_size(&change).unwrap_or(0);
+        if len + change_len > SNAPSHOT_PART_LEN && len > 0 {
+            parts.push(empty(Vec::new()));
+            len = 0;
+        }
+        len += change_len;
+        if let Some(part) = parts.last_mut() {
+            part.changed.push(change);
+        }
+    }
+    let count = parts.len();
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(index, delta)| SnapshotPart {
+            delta,
+            last: index + 1 == count,
+        })
+        .collect()
+}
+
+// How an object differs from the baseline. Most ticks only touch a component or two,
+// usually the transform, so objects the client already has only get those.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub enum ObjectChange {
+    Added(Object),
+    Updated {
+        id: u32,
+        // Components that are new or changed.
+        set: BTreeMap<String, ComponentData>,
+        // Names of components that were taken off.
+        removed: Vec<String>,
+    },
+}
+
+// Objects in `to` that are new or differ from `from`, and ids that are gone from it.
+pub fn diff(from: Option<&WorldState>, to: &WorldState) -> (Vec<ObjectChange>, Vec<u32>) {
+    let changed = to
+        .values()
+        .filter_map(|object| match from.and_then(|from| from.get(&object.id)) {
+            Some(previous) if previous == object => None,
+            Some(previous) => Some(ObjectChange::Updated {
+                id: object.id,
+                set: object
+                    .components
+                    .iter()
+                    .filter(|(name, data)| previous.components.get(*name) != Some(*data))
+                    .map(|(name, data)| (name.clone(), data.clone()))
+                    .collect(),
+                removed: previous
+                    .components
+                    .keys()
+                    .filter(|name| !object.components.contains_key(*name))
+                    .cloned()
+                    .collect(),
+            }),
+            None => Some(ObjectChange::Added(object.clone())),
+        })
+        .collect();
+    let removed = from
+        .map(|from| {
+            from.keys()
+                .filter(|id| !to.contains_key(id))
+                .copied()
+                .collect()
+        })
+        .unwrap_or_default();
+    (changed, removed)
+}
+
+// Ids of objects that were added, changed or removed, collected across state updates
+// until someone takes them.
+#[derive(serde::Serialize, Debug, Default)]
+pub struct StateChanges {
+    pub added: HashSet<u32>,
+    pub changed: HashSet<u32>,
+    pub removed: HashSet<u32>,
+}
+
+impl StateChanges {
+    pub fn record(&mut self, before: &WorldState, after: &WorldState) {
+        for (id, object) in after {
+            match before.get(id) {
+                None => self.mark_added(*id),
+                Some(previous) if previous != object => self.mark_changed(*id),
+                Some(_) => {}
+            }
+        }
+        for id in before.keys().filter(|id| !after.contains_key(id)) {
+            self.mark_removed(*id);
+        }
+    }
+
+    pub fn take(&mut self) -> StateChanges {
+        std::mem::take(self)
+    }
+
+    // Something removed and added back in between is reported as changed, something
+    // added and removed again isn't reported at all.
+    pub fn mark_added(&mut self, id: u32) {
+        if self.removed.remove(&id) {
+            self.changed.insert(id);
+        } else {
+            self.added.insert(id);
+        }
+    }
+
+    pub fn mark_changed(&mut self, id: u32) {
+        if !self.added.contains(&id) {
+            self.changed.insert(id);
+        }
+    }
+
+    pub fn mark_removed(&mut self, id: u32) {
+        self.changed.remove(&id);
+        if !self.added.remove(&id) {
+            self.removed.insert(id);
+        }
+    }
+}
+
+struct Stored {
+    tick: u64,
+    time: f64,
+    state: WorldState,
+}
+
+fn find(history: &VecDeque<Stored>, tick: u64) -> Option<&WorldState> {
+    history
+        .iter()
+        .find(|stored| stored.tick == tick)
+        .map(|stored| &stored.state)
+}
+
+// Server side. Remembers the recent states each client was sent and what it
+// acknowledged, so every client only gets what changed since the last state it is
+// known to have. Clients see different parts of the world, hence a history each.
+#[derive(Default)]
+pub struct SnapshotHistory {
+    // Stamped on every delta, see `SnapshotDelta::epoch`.
+    epoch: u32,
+    clients: HashMap<usize, ClientHistory>,
+}
+
+#[derive(Default)]
+struct ClientHistory {
+    snapshots: VecDeque<Stored>,
+    acked: Option<u64>,
+    // Tick of the last whole snapshot sent over the reliable channel. It will arrive,
+    // so it is as good a baseline as an acknowledged one.
+    full: Option<u64>,
+}
+
+impl SnapshotHistory {
+    pub fn new(epoch: u32) -> Self {
+        Self {
+            epoch,
+            clients: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, client: usize, tick: u64, time: f64, state: WorldState) {
+        let history = self.clients.entry(client).or_default();
+        if history.snapshots.len() == HISTORY_LEN {
+            history.snapshots.pop_front();
+        }
+        history.snapshots.push_back(Stored { tick, time, state });
+    }
+
+    // Acks arrive unreliably and out of order, so only newer ones count.
+    pub fn ack(&mut self, client: usize, tick: u64) {
+        let history = self.clients.entry(client).or_default();
+        history.acked = Some(history.acked.map_or(tick, |acked| acked.max(tick)));
+    }
+
+    pub fn remove_client(&mut self, client: usize) {
+        self.clients.remove(&client);
+    }
+
+    // Delta from the newest state the client is known to have to the latest one pushed
+    // for it, or `None` if nothing was pushed yet. Without a known state the delta
+    // holds the whole world, which should go out through `full_for` instead.
+    pub fn delta_for(&self, client: usize) -> Option<SnapshotDelta> {
+        let history = self.clients.get(&client)?;
+        let latest = history.snapshots.back()?;
+        let baseline = history
+            .acked
+            .into_iter()
+            .chain(history.full)
+            .filter_map(|tick| find(&history.snapshots, tick).map(|state| (tick, state)))
+            .max_by_key(|(tick, _)| *tick);
+        let (changed, removed) = diff(baseline.map(|(_, state)| state), &latest.state);
+        Some(SnapshotDelta {
+            epoch: self.epoch,
+            tick: latest.tick,
+            time: latest.time,
+            baseline: baseline.map(|(tick, _)| tick),
+            changed,
+            removed,
+        })
+    }
+
+    // The whole latest state pushed for the client, to be sent reliably. Later deltas
+    // are computed against it until the client acknowledges something newer.
+    pub fn full_for(&mut self, client: usize) -> Option<SnapshotDelta> {
+        let history = self.clients.get_mut(&client)?;
+        let latest = history.snapshots.back()?;
+        let (changed, removed) = diff(None, &latest.state);
+        history.full = Some(latest.tick);
+        Some(SnapshotDelta {
+            epoch: self.epoch,
+            tick: latest.tick,
+            time: latest.time,
+            baseline: None,
+            changed,
+            removed,
+        })
+    }
+}
+
+// Client side. Rebuilds world states from deltas and keeps the recent ones around
+// since the server may still be diffing against any of them.
+#[derive(Default)]
+pub struct SnapshotReceiver {
+    snapshots: VecDeque<Stored>,
+    // The parts of a split snapshot that came in so far.
+    partial: Option<SnapshotDelta>,
+}
+
+impl SnapshotReceiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Applies a delta and returns the tick to acknowledge. Deltas that are older than
+    // what we already have, or against a state we no longer know, are ignored.
+    pub fn apply(&mut self, delta: SnapshotDelta) -> Option<u64> {
+        if let Some(latest) = self.snapshots.back() {
+            if delta.tick <= latest.tick {
+                return None;
+            }
+        }
+        let mut state = match delta.baseline {
+            Some(baseline) => find(&self.snapshots, baseline)?.clone(),
+            None => WorldState::new(),
+        };
+        for id in delta.removed {
+            state.remove(&id);
+        }
+        for change in delta.changed {
+            match change {
+                ObjectChange::Added(object) => {
+                    state.insert(object.id, object);
+                }
+                ObjectChange::Updated { id, set, removed } => {
+                    if let Some(object) = state.get_mut(&id) {
+                        for name in removed {
+                            object.components.remove(&name);
+                        }
+                        object.components.extend(set);
+                    }
+                }
+            }
+        }
+        if self.snapshots.len() == HISTORY_LEN {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(Stored {
+            tick: delta.tick,
+            time: delta.time,
+            state,
+        });
+        Some(delta.tick)
+    }
+
+    // Collects the parts of a split snapshot and applies it once the last one is in.
+    // They come over the reliable channel, so in order and one snapshot at a time.
+    pub fn apply_part(&mut self, part: SnapshotPart) -> Option<u64> {
+        let delta = match self.partial.take() {
+            Some(mut partial) if partial.tick == part.delta.tick => {
+                partial.changed.extend(part.delta.changed);
+                partial.removed.extend(part.delta.removed);
+                partial
+            }
+            _ => part.delta,
+        };
+        if part.last {
+            self.apply(delta)
+        } else {
+            self.partial = Some(delta);
+            None
+        }
+    }
+
+    pub fn latest(&self) -> Option<&WorldState> {
+        self.snapshots.back().map(|stored| &stored.state)
+    }
+
+    // Server clock of the latest state.
+    pub fn latest_time(&self) -> Option<f64> {
+        self.snapshots.back().map(|stored| stored.time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::component::{Renderable, Shape, Transform};
+
+    fn object(id: u32, position: (f32, f32)) -> Object {
+        Object::new(id).with(Transform { position })
+    }
+
+    fn world(objects: Vec<Object>) -> WorldState {
+        objects
+            .into_iter()
+            .map(|object| (object.id, object))
+            .collect()
+    }
+
+    fn delta(tick: u64, baseline: Option<(u64, &WorldState)>, to: &WorldState) -> SnapshotDelta {
+        let (changed, removed) = diff(baseline.map(|(_, state)| state), to);
+        SnapshotDelta {
+            epoch: 0,
+            tick,
+            time: tick as f64,
+            baseline: baseline.map(|(tick, _)| tick),
+            changed,
+            removed,
+        }
+    }
+
+    fn tree() -> Renderable {
+        Renderable {
+            shape: Shape::Tree,
+            size: 5.0,
+            color: "green".to_string(),
+        }
+    }
+
+    #[test]
+    fn applying_a_diff_rebuilds_the_target_state() {
+        let first = world(vec![
+            object(1, (0.0, 0.0)),
+            object(2, (5.0, 5.0)).with(tree()),
+            object(3, (9.0, 9.0)),
+        ]);
+        let mut moved = object(2, (6.0, 5.0));
+        moved.remove::<Transform>();
+        let second = world(vec![object(1, (1.0, 0.0)), moved, object(4, (2.0, 2.0))]);
+
+        let mut receiver = SnapshotReceiver::new();
+        assert_eq!(receiver.apply(delta(1, None, &first)), Some(1));
+        assert_eq!(receiver.latest(), Some(&first));
+        assert_eq!(
+            receiver.apply(delta(2, Some((1, &first)), &second)),
+            Some(2)
+        );
+        assert_eq!(receiver.latest(), Some(&second));
+        assert_eq!(receiver.latest_time(), Some(2.0));
+    }
+
+    #[test]
+    fn unchanged_objects_are_left_out() {
+        let first = world(vec![object(1, (0.0, 0.0)), object(2, (5.0, 5.0))]);
+        let second = world(vec![object(1, (1.0, 0.0)), object(2, (5.0, 5.0))]);
+        let (changed, removed) = diff(Some(&first), &second);
+        assert_eq!(changed.len(), 1);
+        assert!(
+            matches!(&changed[0], ObjectChange::Updated { id: 1, removed, .. } if removed.is_empty())
+        );
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn older_deltas_are_ignored() {
+        let first = world(vec![object(1, (0.0, 0.0))]);
+        let second = world(vec![object(1, (1.0, 0.0))]);
+        let mut receiver = SnapshotReceiver::new();
+        assert_eq!(receiver.apply(delta(1, None, &first)), Some(1));
+        assert_eq!(
+            receiver.apply(delta(3, Some((1, &first)), &second)),
+            Some(3)
+        );
+        // Tick 2 was overtaken by 3 on the way.
+        assert_eq!(receiver.apply(delta(2, Some((1, &first)), &first)), None);
+        assert_eq!(receiver.apply(delta(3, Some((1, &first)), &first)), None);
+        assert_eq!(receiver.latest(), Some(&second));
+    }
+
+    #[test]
+    fn deltas_against_unknown_states_are_ignored() {
+        let first = world(vec![object(1, (0.0, 0.0))]);
+        let second = world(vec![object(1, (1.0, 0.0))]);
+        let mut receiver = SnapshotReceiver::new();
+        assert_eq!(receiver.apply(delta(2, Some((1, &first)), &second)), None);
+        assert_eq!(receiver.latest(), None);
+
+        receiver.apply(delta(1, None, &first));
+        assert_eq!(receiver.apply(delta(3, Some((2, &second)), &second)), None);
+        assert_eq!(receiver.latest(), Some(&first));
+    }
+
+    #[test]
+    fn split_snapshots_are_applied_once_complete() {
+        let state = world(
+            (0..2000)
+                .map(|id| object(id, (id as f32, 0.0)).with(tree()))
+                .collect(),
+        );
+        let full = delta(5, None, &state);
+        assert!(!fits_unreliable(&full));
+
+        let parts = split(full);
+        assert!(parts.len() > 1);
+        let (last, rest) = parts.split_last().unwrap();
+        assert!(last.last && rest.iter().all(|part| !part.last));
+
+        let mut receiver = SnapshotReceiver::new();
+        for part in rest {
+            let len = bincode::serialized_size(part).unwrap();
+            assert!(len < 2 * SNAPSHOT_PART_LEN);
+            assert_eq!(receiver.apply_part(part.clone()), None);
+        }
+        assert_eq!(receiver.apply_part(last.clone()), Some(5));
+        assert_eq!(receiver.latest(), Some(&state));
+    }
+
+    #[test]
+    fn deltas_build_on_the_last_full_snapshot_until_acked() {
+        let first = world(vec![object(1, (0.0, 0.0))]);
+        let second = world(vec![object(1, (1.0, 0.0))]);
+        let mut history = SnapshotHistory::new(0);
+        history.push(7, 1, 1.0, first.clone());
+        assert_eq!(history.delta_for(7).unwrap().baseline, None);
+        assert_eq!(history.full_for(7).unwrap().tick, 1);
+
+        history.push(7, 2, 2.0, second.clone());
+        assert_eq!(history.delta_for(7).unwrap().baseline, Some(1));
+        history.ack(7, 2);
+        history.push(7, 3, 3.0, second);
+        assert_eq!(history.delta_for(7).unwrap().baseline, Some(2));
+
+        // Once neither is in the history any more, only a whole snapshot will do.
+        for tick in 4..4 + HISTORY_LEN as u64 {
+            history.push(7, tick, tick as f64, first.clone());
+        }
+        assert_eq!(history.delta_for(7).unwrap().baseline, None);
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::{HashMap, HashSet};
+
+type Cell = (i32, i32);
+
+// Uniform grid over object positions so range and point queries only look at the
+// cells they overlap instead of every object in the world.
+pub struct SpatialGrid {
+    cell_size: f32,
+    cells: HashMap<Cell, HashSet<u32>>,
+    positions: HashMap<u32, (f32, f32)>,
+}
+
+impl Default for SpatialGrid {
+    fn default() -> Self {
+        Self::new(50.0)
+    }
+}
+
+impl SpatialGrid {
+    pub fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size,
+            cells: HashMap::new(),
+            positions: HashMap::new(),
+        }
+    }
+
+    // Adds an object, or moves it if it is already indexed.
+    pub fn insert(&mut self, id: u32, position: (f32, f32)) {
+        let cell = self.cell(position);
+        if let Some(previous) = self.positions.insert(id, position) {
+            let previous = self.cell(previous);
+            if previous == cell {
+                return;
+            }
+            self.remove_from_cell(previous, id);
+        }
+        self.cells.entry(cell).or_default().insert(id);
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        if let Some(position) = self.positions.remove(&id) {
+            let cell = self.cell(position);
+            self.remove_from_cell(cell, id);
+        }
+    }
+
+    pub fn position(&self, id: u32) -> Option<(f32, f32)> {
+        self.positions.get(&id).copied()
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.positions.keys().copied()
+    }
+
+    // Objects inside the rectangle spanned by `min` and `max`, edges included.
+    pub fn in_rect(&self, min: (f32, f32), max: (f32, f32)) -> Vec<u32> {
+        let (low, high) = (self.cell(min), self.cell(max));
+        let mut found = Vec::new();
+        for x in low.0..=high.0 {
+            for y in low.1..=high.1 {
+                let ids = match self.cells.get(&(x, y)) {
+                    Some(ids) => ids,
+                    None => continue,
+                };
+                found.extend(ids.iter().copied().filter(|id| {
+                    let (px, py) = self.positions[id];
+                    px >= min.0 && px <= max.0 && py >= min.1 && py <= max.1
+                }));
+            }
+        }
+        found
+    }
+
+    // Objects no further than `radius` from `center`.
+    pub fn in_radius(&self, center: (f32, f32), radius: f32) -> Vec<u32> {
+        let min = (center.0 - radius, center.1 - radius);
+        let max = (center.0 + radius, center.1 + radius);
+        let mut found = self.in_rect(min, max);
+        found.retain(|id| distance_squared(center, self.positions[id]) <= radius * radius);
+        found
+    }
+
+    // The object closest to `point`, if any is within `max_distance` of it.
+    pub fn nearest(&self, point: (f32, f32), max_distance: f32) -> Option<u32> {
+        self.in_radius(point, max_distance)
+            .into_iter()
+            .map(|id| (id, distance_squared(point, self.positions[&id])))
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(id, _)| id)
+    }
+
+    fn cell(&self, position: (f32, f32)) -> Cell {
+        (
+            (position.0 / self.cell_size).floor() as i32,
+            (position.1 / self.cell_size).floor() as i32,
+        )
+    }
+
+    fn remove_from_cell(&mut self, cell: Cell, id: u32) {
+        if let Some(ids) = self.cells.get_mut(&cell) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.cells.remove(&cell);
+            }
+        }
+    }
+}
+
+fn distance_squared(a: (f32, f32), b: (f32, f32)) -> f32 {
+    let (dx, dy) = (a.0 - b.0, a.1 - b.1);
+    dx * dx + dy * dy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(mut ids: Vec<u32>) -> Vec<u32> {
+        ids.sort_unstable();
+        ids
+    }
+
+    // Every indexed object sits in exactly the cell its position maps to.
+    fn assert_consistent(grid: &SpatialGrid) {
+        let in_cells: usize = grid.cells.values().map(HashSet::len).sum();
+        assert_eq!(in_cells, grid.positions.len());
+        for (id, position) in &grid.positions {
+            assert!(grid.cells[&grid.cell(*position)].contains(id));
+        }
+        assert!(grid.cells.values().all(|ids| !ids.is_empty()));
+    }
+
+    #[test]
+    fn moving_within_a_cell_keeps_it_there() {
+        let mut grid = SpatialGrid::new(10.0);
+        grid.insert(1, (1.0, 1.0));
+        grid.insert(1, (9.0, 9.0));
+        assert_consistent(&grid);
+        assert_eq!(grid.position(1), Some((9.0, 9.0)));
+        assert_eq!(grid.cells.len(), 1);
+    }
+
+    #[test]
+    fn moving_into_another_cell_leaves_the_old_one() {
+        let mut grid = SpatialGrid::new(10.0);
+        grid.insert(1, (1.0, 1.0));
+        grid.insert(2, (2.0, 2.0));
+        grid.insert(1, (25.0, -5.0));
+        assert_consistent(&grid);
+        assert_eq!(grid.in_rect((0.0, 0.0), (9.0, 9.0)), vec![2]);
+        assert_eq!(grid.in_rect((20.0, -10.0), (30.0, 0.0)), vec![1]);
+    }
+
+    #[test]
+    fn removing_drops_empty_cells() {
+        let mut grid = SpatialGrid::new(10.0);
+        grid.insert(1, (1.0, 1.0));
+        grid.insert(2, (15.0, 1.0));
+        grid.remove(1);
+        grid.remove(3);
+        assert_consistent(&grid);
+        assert_eq!(grid.position(1), None);
+        assert_eq!(grid.cells.len(), 1);
+        assert_eq!(grid.ids().collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn in_radius_checks_distance_not_just_cells() {
+        let mut grid = SpatialGrid::new(10.0);
+        grid.insert(1, (0.0, 0.0));
+        grid.insert(2, (7.0, 7.0));
+        grid.insert(3, (10.0, 0.0));
+        grid.insert(4, (30.0, 30.0));
+        // 2 is about 9.9 away, so it only makes the larger radius.
+        assert_eq!(sorted(grid.in_radius((0.0, 0.0), 10.0)), vec![1, 2, 3]);
+        assert_eq!(sorted(grid.in_radius((0.0, 0.0), 9.0)), vec![1]);
+        assert_eq!(grid.in_radius((100.0, 100.0), 5.0), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn nearest_picks_the_closest_within_range() {
+        let mut grid = SpatialGrid::new(10.0);
+        grid.insert(1, (0.0, 0.0));
+        grid.insert(2, (12.0, 0.0));
+        grid.insert(3, (18.0, 0.0));
+        assert_eq!(grid.nearest((14.0, 0.0), 10.0), Some(2));
+        assert_eq!(grid.nearest((17.0, 1.0), 10.0), Some(3));
+        assert_eq!(grid.nearest((50.0, 50.0), 10.0), None);
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::runtime::{RuntimeInstant, RuntimeInstantHandler};
+
+// How often the per second rates are recomputed.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+// Same smoothing turbulence uses for its own reliable channel RTT estimate.
+const RTT_UPDATE_FACTOR: f64 = 0.1;
+const LOSS_UPDATE_FACTOR: f64 = 0.25;
+
+// A snapshot of how healthy a single connection is. Times are in milliseconds so the
+// struct can be handed straight to JS.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct ConnectionStats {
+    // Smoothed round trip time, `None` until the first ping comes back.
+    pub rtt_ms: Option<f64>,
+    pub bytes_in_per_sec: f64,
+    pub bytes_out_per_sec: f64,
+    pub packets_in_per_sec: f64,
+    pub packets_out_per_sec: f64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    // Smoothed fraction of our packets the peer reports never arriving. turbulence
+    // doesn't expose how often the reliable channel resends, this is the closest proxy.
+    pub packet_loss: f64,
+    // Messages waiting to be handed to turbulence.
+    pub outgoing_queue: usize,
+    // Packets received from the transport that haven't been processed yet.
+    pub incoming_queue: usize,
+    // Time since anything was last received from the peer, `None` if nothing has been.
+    pub last_heard_ms: Option<f64>,
+}
+
+// How long the server took to simulate a stretch of ticks. Rooms send these to their
+// clients now and then, so anyone watching can tell a struggling server from a bad
+// connection. Times are in milliseconds.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct TickStats {
+    // Epoch of the room that measured them.
+    pub epoch: u32,
+    // The last tick they cover.
+    pub tick: u64,
+    pub ticks: u32,
+    // How long a tick may take without falling behind.
+    pub budget_ms: f32,
+    pub mean_ms: f32,
+    pub max_ms: f32,
+    // Ticks that took longer than `budget_ms`.
+    pub overruns: u32,
+    // Ticks the server dropped to catch up after falling behind.
+    pub skipped: u32,
+}
+
+impl TickStats {
+    pub fn record(&mut self, elapsed_ms: f32, budget_ms: f32) {
+        self.ticks += 1;
+        self.mean_ms += (elapsed_ms - self.mean_ms) / self.ticks as f32;
+        self.max_ms = self.max_ms.max(elapsed_ms);
+        self.budget_ms = budget_ms;
+        if elapsed_ms > budget_ms {
+            self.overruns += 1;
+        }
+    }
+
+    pub fn record_skipped(&mut self, ticks: u32) {
+        self.skipped += ticks;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Totals {
+    bytes_in: u64,
+    bytes_out: u64,
+    packets_in: u64,
+    packets_out: u64,
+}
+
+struct PendingPing {
+    id: u32,
+    sent_at: RuntimeInstant,
+    packets_out: u64,
+}
+
+// Lives inside the `MessageProcessor` and keeps the shared `ConnectionStats` current.
+pub(crate) struct StatsTracker {
+    shared: Arc<Mutex<ConnectionStats>>,
+    totals: Totals,
+    window_start: RuntimeInstant,
+    window_totals: Totals,
+    last_heard: Option<RuntimeInstant>,
+    rtt: Option<f64>,
+    loss: f64,
+    next_ping_id: u32,
+    pending_ping: Option<PendingPing>,
+    // Our sent count and the peer's received count as of the last answered ping.
+    last_pong: Option<(u64, u64)>,
+}
+
+impl StatsTracker {
+    pub fn new(now: RuntimeInstant) -> Self {
+        Self {
+            shared: Arc::new(Mutex::new(ConnectionStats::default())),
+            totals: Totals::default(),
+            window_start: now,
+            window_totals: Totals::default(),
+            last_heard: None,
+            rtt: None,
+            loss: 0.0,
+            next_ping_id: 0,
+            pending_ping: None,
+            last_pong: None,
+        }
+    }
+
+    pub fn shared(&self) -> Arc<Mutex<ConnectionStats>> {
+        self.shared.clone()
+    }
+
+    pub fn packets_in(&self) -> u64 {
+        self.totals.packets_in
+    }
+
+    pub fn last_heard(&self) -> Option<RuntimeInstant> {
+        self.last_heard
+    }
+
+    pub fn record_incoming(&mut self, now: RuntimeInstant, bytes: usize) {
+        self.totals.packets_in += 1;
+        self.totals.bytes_in += bytes as u64;
+        self.last_heard = Some(now);
+    }
+
+    pub fn record_outgoing(&mut self, bytes: usize) {
+        self.totals.packets_out += 1;
+        self.totals.bytes_out += bytes as u64;
+    }
+
+    // Starts a new RTT sample and returns the id to put in the ping.
+    pub fn start_ping(&mut self, now: RuntimeInstant) -> u32 {
+        let id = self.next_ping_id;
+        self.next_ping_id = self.next_ping_id.wrapping_add(1);
+        self.pending_ping = Some(PendingPing {
+            id,
+            sent_at: now,
+            packets_out: self.totals.packets_out,
+        });
+        id
+    }
+
+    pub fn finish_ping(&mut self, now: RuntimeInstant, id: u32, peer_packets_in: u64) {
+        let ping = match self.pending_ping.take() {
+            Some(ping) if ping.id == id => ping,
+            other => {
+                // Stale or unknown pong, keep waiting for the current one.
+                self.pending_ping = other;
+                return;
+            }
+        };
+        let sample = RuntimeInstantHandler::duration_between(ping.sent_at, now).as_secs_f64();
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt + (sample - rtt) * RTT_UPDATE_FACTOR,
+            None => sample,
+        });
+
+        if let Some((last_packets_out, last_peer_packets_in)) = self.last_pong {
+            let sent = ping.packets_out.saturating_sub(last_packets_out);
+            let arrived = peer_packets_in.saturating_sub(last_peer_packets_in);
+            if sent > 0 {
+                let sample = 1.0 - (arrived as f64 / sent as f64).min(1.0);
+                self.loss += (sample - self.loss) * LOSS_UPDATE_FACTOR;
+            }
+        }
+        self.last_pong = Some((ping.packets_out, peer_packets_in));
+    }
+
+    // Recomputes rates once per window and publishes the current snapshot.
+    pub fn refresh(&mut self, now: RuntimeInstant, outgoing_queue: usize, incoming_queue: usize) {
+        let mut shared = self.shared.lock().unwrap();
+        let window = RuntimeInstantHandler::duration_between(self.window_start, now);
+        if window >= RATE_WINDOW {
+            let seconds = window.as_secs_f64();
+            let rate = |current: u64, previous: u64| (current - previous) as f64 / seconds;
+            shared.bytes_in_per_sec = rate(self.totals.bytes_in, self.window_totals.bytes_in);
+            shared.bytes_out_per_sec = rate(self.totals.bytes_out, self.window_totals.bytes_out);
+            shared.packets_in_per_sec = rate(self.totals.packets_in, self.window_totals.packets_in);
+            shared.packets_out_per_sec =
+                rate(self.totals.packets_out, self.window_totals.packets_out);
+            self.window_start = now;
+            self.window_totals = self.totals;
+        }
+        shared.rtt_ms = self.rtt.map(|rtt| rtt * 1000.0);
+        shared.bytes_in = self.totals.bytes_in;
+        shared.bytes_out = self.totals.bytes_out;
+        shared.packets_in = self.totals.packets_in;
+        shared.packets_out = self.totals.packets_out;
+        shared.packet_loss = self.loss;
+        shared.outgoing_queue = outgoing_queue;
+        shared.incoming_queue = incoming_queue;
+        shared.last_heard_ms = self.last_heard.map(|last_heard| {
+            RuntimeInstantHandler::duration_between(last_heard, now).as_secs_f64() * 1000.0
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Instants `ms` milliseconds apart from a common start.
+    fn clock() -> impl Fn(u64) -> RuntimeInstant {
+        let start = instant::Instant::now();
+        move |ms| RuntimeInstant::from(start + Duration::from_millis(ms))
+    }
+
+    fn published(tracker: &mut StatsTracker, now: RuntimeInstant) -> ConnectionStats {
+        tracker.refresh(now, 0, 0);
+        let stats = tracker.shared().lock().unwrap().clone();
+        stats
+    }
+
+    fn assert_close(value: f64, expected: f64) {
+        assert!((value - expected).abs() < 1e-9, "{} != {}", value, expected);
+    }
+
+    #[test]
+    fn rates_are_computed_once_per_window() {
+        let at = clock();
+        let mut tracker = StatsTracker::new(at(0));
+        for i in 0..10 {
+            tracker.record_incoming(at(i * 50), 100);
+        }
+        for _ in 0..5 {
+            tracker.record_outgoing(50);
+        }
+
+        let stats = published(&mut tracker, at(500));
+        assert_eq!((stats.bytes_in, stats.packets_in), (1000, 10));
+        assert_eq!((stats.bytes_out, stats.packets_out), (250, 5));
+        assert_eq!(stats.bytes_in_per_sec, 0.0);
+
+        let stats = published(&mut tracker, at(1000));
+        assert_close(stats.bytes_in_per_sec, 1000.0);
+        assert_close(stats.packets_in_per_sec, 10.0);
+        assert_close(stats.bytes_out_per_sec, 250.0);
+        assert_close(stats.packets_out_per_sec, 5.0);
+        assert_close(stats.last_heard_ms.unwrap(), 550.0);
+
+        // A quiet window brings the rates back down, the totals stay.
+        let stats = published(&mut tracker, at(2000));
+        assert_eq!(stats.bytes_in_per_sec, 0.0);
+        assert_eq!(stats.packets_out_per_sec, 0.0);
+        assert_eq!(stats.bytes_in, 1000);
+    }
+
+    #[test]
+    fn rtt_is_smoothed_and_stale_pongs_are_ignored() {
+        let at = clock();
+        let mut tracker = StatsTracker::new(at(0));
+        assert_eq!(published(&mut tracker, at(0)).rtt_ms, None);
+
+        let id = tracker.start_ping(at(0));
+        tracker.finish_ping(at(100), id, 0);
+        assert_close(published(&mut tracker, at(100)).rtt_ms.unwrap(), 100.0);
+
+        let id = tracker.start_ping(at(1000));
+        tracker.finish_ping(at(1100), id.wrapping_add(1), 0);
+        tracker.finish_ping(at(1200), id, 0);
+        assert_close(published(&mut tracker, at(1200)).rtt_ms.unwrap(), 110.0);
+    }
+
+    #[test]
+    fn loss_compares_our_sent_packets_with_what_the_peer_got() {
+        let at = clock();
+        let mut tracker = StatsTracker::new(at(0));
+        let id = tracker.start_ping(at(0));
+        tracker.finish_ping(at(10), id, 0);
+        assert_eq!(published(&mut tracker, at(10)).packet_loss, 0.0);
+
+        for _ in 0..100 {
+            tracker.record_outgoing(10);
+        }
+        let id = tracker.start_ping(at(1000));
+        tracker.finish_ping(at(1010), id, 80);
+        let expected = 0.2 * LOSS_UPDATE_FACTOR;
+        assert_close(published(&mut tracker, at(1010)).packet_loss, expected);
+
+        // Nothing sent since the last pong says nothing about loss.
+        let id = tracker.start_ping(at(2000));
+        tracker.finish_ping(at(2010), id, 80);
+        assert_close(published(&mut tracker, at(2010)).packet_loss, expected);
+    }
+}
@@ -0,0 +1,117 @@
+// Framing for clients that talk to the server over a plain UDP socket instead of a
+// WebRTC data channel. Every datagram starts with a kind byte. A client says hello
+// until the server welcomes it, after that both sides send data packets holding the
+// same turbulence packets a data channel would carry.
+
+// Largest payload a UDP datagram can carry.
+pub const MAX_DATAGRAM_LEN: usize = 65_507;
+pub const PROTOCOL_MAGIC: [u8; 4] = *b"FBNT";
+pub const PROTOCOL_VERSION: u8 = 1;
+
+const HELLO: u8 = 1;
+const WELCOME: u8 = 2;
+const DATA: u8 = 3;
+const BYE: u8 = 4;
+
+#[derive(Debug, PartialEq)]
+pub enum Datagram<'a> {
+    Hello,
+    Welcome,
+    Data(&'a [u8]),
+    Bye,
+}
+
+impl<'a> Datagram<'a> {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Datagram::Hello => handshake(HELLO),
+            Datagram::Welcome => handshake(WELCOME),
+            Datagram::Data(payload) => {
+                let mut bytes = Vec::with_capacity(payload.len() + 1);
+                bytes.push(DATA);
+                bytes.extend_from_slice(payload);
+                bytes
+            }
+            Datagram::Bye => vec![BYE],
+        }
+    }
+
+    // `None` for anything that isn't ours, including handshakes from other versions.
+    pub fn decode(bytes: &'a [u8]) -> Option<Self> {
+        let (kind, rest) = bytes.split_first()?;
+        match *kind {
+            HELLO if is_handshake(rest) => Some(Datagram::Hello),
+            WELCOME if is_handshake(rest) => Some(Datagram::Welcome),
+            DATA => Some(Datagram::Data(rest)),
+            BYE => Some(Datagram::Bye),
+            _ => None,
+        }
+    }
+}
+
+fn handshake(kind: u8) -> Vec<u8> {
+    let mut bytes = vec![kind];
+    bytes.extend_from_slice(&PROTOCOL_MAGIC);
+    bytes.push(PROTOCOL_VERSION);
+    bytes
+}
+
+fn is_handshake(bytes: &[u8]) -> bool {
+    bytes.len() == PROTOCOL_MAGIC.len() + 1
+        && bytes[..PROTOCOL_MAGIC.len()] == PROTOCOL_MAGIC
+        && bytes[PROTOCOL_MAGIC.len()] == PROTOCOL_VERSION
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_kind_survives_a_round_trip() {
+        let payload = [0, 1, 2, 0xff];
+        let datagrams = [
+            Datagram::Hello,
+            Datagram::Welcome,
+            Datagram::Data(&payload),
+            Datagram::Data(&[]),
+            Datagram::Bye,
+        ];
+        for datagram in &datagrams {
+            let bytes = datagram.encode();
+            assert_eq!(Datagram::decode(&bytes).as_ref(), Some(datagram));
+        }
+    }
+
+    #[test]
+    fn data_is_a_kind_byte_in_front_of_the_payload() {
+        assert_eq!(Datagram::Data(&[7, 8]).encode(), vec![DATA, 7, 8]);
+        assert_eq!(Datagram::Bye.encode(), vec![BYE]);
+    }
+
+    #[test]
+    fn unknown_kinds_are_ignored() {
+        assert_eq!(Datagram::decode(&[]), None);
+        assert_eq!(Datagram::decode(&[0]), None);
+        assert_eq!(Datagram::decode(&[BYE + 1, 1, 2]), None);
+    }
+
+    #[test]
+    fn handshakes_from_other_protocols_are_ignored() {
+        let hello = Datagram::Hello.encode();
+
+        let mut wrong_magic = hello.clone();
+        wrong_magic[1..=PROTOCOL_MAGIC.len()].copy_from_slice(b"XXXX");
+        assert_eq!(Datagram::decode(&wrong_magic), None);
+
+        let mut wrong_version = hello.clone();
+        *wrong_version.last_mut().unwrap() = PROTOCOL_VERSION + 1;
+        assert_eq!(Datagram::decode(&wrong_version), None);
+
+        // Cut short or with anything trailing.
+        assert_eq!(Datagram::decode(&[HELLO]), None);
+        assert_eq!(Datagram::decode(&hello[..hello.len() - 1]), None);
+        let mut trailing = Datagram::Welcome.encode();
+        trailing.push(0);
+        assert_eq!(Datagram::decode(&trailing), None);
+    }
+}
@@ -0,0 +1,361 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use futures_util::{
+    future::BoxFuture,
+    task::{waker_ref, ArcWake},
+    FutureExt,
+};
+
+use crate::runtime::{Runtime, RuntimeInstant};
+
+// A single threaded runtime whose clock only moves when it is told to. Every spawned
+// task and every sleep (including turbulence's resend timers and the processor flush
+// loop) runs against the virtual clock, so tests can step time forward without
+// sleeping and without depending on how loaded the machine is.
+#[derive(Clone)]
+pub struct VirtualRuntime {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    epoch: instant::Instant,
+    clock: Mutex<Duration>,
+    timers: Mutex<BTreeMap<(Duration, u64), Waker>>,
+    next_timer: AtomicU64,
+    ready: Mutex<VecDeque<Arc<Task>>>,
+}
+
+struct Task {
+    future: Mutex<Option<BoxFuture<'static, ()>>>,
+    scheduled: AtomicBool,
+    inner: Weak<Inner>,
+}
+
+impl ArcWake for Task {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        if arc_self.scheduled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Wakeups that arrive after the runtime is gone have nowhere to go.
+        if let Some(inner) = arc_self.inner.upgrade() {
+            inner.ready.lock().unwrap().push_back(arc_self.clone());
+        }
+    }
+}
+
+impl VirtualRuntime {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                epoch: instant::Instant::now(),
+                clock: Mutex::new(Duration::from_secs(0)),
+                timers: Mutex::new(BTreeMap::new()),
+                next_timer: AtomicU64::new(0),
+                ready: Mutex::new(VecDeque::new()),
+            }),
+        }
+    }
+
+    // Virtual time passed since the runtime was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.inner.clock.lock().unwrap()
+    }
+
+    // Polls spawned tasks until none of them can make progress without the clock moving.
+    pub fn run_until_stalled(&self) {
+        loop {
+            let task = self.inner.ready.lock().unwrap().pop_front();
+            let task = match task {
+                Some(task) => task,
+                None => break,
+            };
+            task.scheduled.store(false, Ordering::SeqCst);
+            let mut slot = task.future.lock().unwrap();
+            if let Some(mut future) = slot.take() {
+                let waker = waker_ref(&task);
+                let mut context = Context::from_waker(&waker);
+                if future.as_mut().poll(&mut context).is_pending() {
+                    *slot = Some(future);
+                }
+            }
+        }
+    }
+
+    // Moves the clock forward by `duration`, stopping at every timer deadline on the
+    // way so tasks observe time passing in the same order they would for real.
+    pub fn advance(&self, duration: Duration) {
+        let target = self.elapsed() + duration;
+        self.run_until_stalled();
+        while let Some(deadline) = self.next_deadline() {
+            if deadline > target {
+                break;
+            }
+            self.set_clock(deadline);
+            self.run_until_stalled();
+        }
+        self.set_clock(target);
+        self.run_until_stalled();
+    }
+
+    // Drives `future` to completion alongside every spawned task. Whenever everything
+    // is stalled the clock jumps straight to the next timer.
+    //
+    // Panics if the future can never finish because nothing is left to wake it.
+    pub fn block_on<F>(&self, future: F) -> F::Output
+    where
+        F: Future,
+    {
+        let woken = Arc::new(Flag(AtomicBool::new(true)));
+        let waker = waker_ref(&woken);
+        let mut context = Context::from_waker(&waker);
+        futures_util::pin_mut!(future);
+        loop {
+            if woken.0.swap(false, Ordering::SeqCst) {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                    return output;
+                }
+            }
+            self.run_until_stalled();
+            if woken.0.load(Ordering::SeqCst) {
+                continue;
+            }
+            match self.next_deadline() {
+                Some(deadline) => {
+                    self.set_clock(deadline.max(self.elapsed()));
+                }
+                None => panic!("Virtual runtime stalled with no pending timers"),
+            }
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Duration> {
+        self.inner
+            .timers
+            .lock()
+            .unwrap()
+            .keys()
+            .next()
+            .map(|(deadline, _)| *deadline)
+    }
+
+    fn set_clock(&self, now: Duration) {
+        *self.inner.clock.lock().unwrap() = now;
+        let mut timers = self.inner.timers.lock().unwrap();
+        let pending = timers.split_off(&(now + Duration::from_nanos(1), 0));
+        let expired = std::mem::replace(&mut *timers, pending);
+        drop(timers);
+        for (_, waker) in expired {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for VirtualRuntime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Flag(AtomicBool);
+
+impl ArcWake for Flag {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+pub struct VirtualSleep {
+    runtime: VirtualRuntime,
+    deadline: Duration,
+    id: u64,
+}
+
+impl Future for VirtualSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.runtime.elapsed() >= self.deadline {
+            return Poll::Ready(());
+        }
+        self.runtime
+            .inner
+            .timers
+            .lock()
+            .unwrap()
+            .insert((self.deadline, self.id), cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for VirtualSleep {
+    fn drop(&mut self) {
+        self.runtime
+            .inner
+            .timers
+            .lock()
+            .unwrap()
+            .remove(&(self.deadline, self.id));
+    }
+}
+
+impl Runtime for VirtualRuntime {
+    type Sleep = VirtualSleep;
+
+    fn spawn<F>(&self, future: F)
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(future.boxed())),
+            scheduled: AtomicBool::new(false),
+            inner: Arc::downgrade(&self.inner),
+        });
+        ArcWake::wake_by_ref(&task);
+    }
+
+    fn sleep(&self, duration: std::time::Duration) -> Self::Sleep {
+        VirtualSleep {
+            runtime: self.clone(),
+            deadline: self.elapsed() + duration,
+            id: self.inner.next_timer.fetch_add(1, Ordering::SeqCst),
+        }
+    }
+
+    fn now(&self) -> RuntimeInstant {
+        RuntimeInstant::from(self.inner.epoch + self.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        event::{ConnectionEvent, DisconnectReason},
+        loopback::{LinkConditioner, LoopbackNetwork},
+        message::GameProtocol,
+        multiplexer::ConnectionMultiplexer,
+        processor::ConnectionConfig,
+    };
+
+    fn record_after(runtime: &VirtualRuntime, fired: &Arc<Mutex<Vec<u64>>>, millis: u64) {
+        let fired = fired.clone();
+        let sleep = runtime.sleep(Duration::from_millis(millis));
+        runtime.spawn(async move {
+            sleep.await;
+            fired.lock().unwrap().push(millis);
+        });
+    }
+
+    #[test]
+    fn sleeps_fire_in_deadline_order() {
+        let runtime = VirtualRuntime::new();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        for millis in &[30, 10, 20] {
+            record_after(&runtime, &fired, *millis);
+        }
+        runtime.advance(Duration::from_millis(25));
+        assert_eq!(*fired.lock().unwrap(), vec![10, 20]);
+        runtime.advance(Duration::from_millis(25));
+        assert_eq!(*fired.lock().unwrap(), vec![10, 20, 30]);
+        assert_eq!(runtime.elapsed(), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn set_clock_only_wakes_expired_timers() {
+        let runtime = VirtualRuntime::new();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        record_after(&runtime, &fired, 10);
+        record_after(&runtime, &fired, 20);
+        runtime.run_until_stalled();
+        runtime.set_clock(Duration::from_millis(15));
+        runtime.run_until_stalled();
+        assert_eq!(*fired.lock().unwrap(), vec![10]);
+        assert_eq!(runtime.next_deadline(), Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn block_on_jumps_to_the_next_timer() {
+        let runtime = VirtualRuntime::new();
+        runtime.block_on(runtime.sleep(Duration::from_secs(60)));
+        assert_eq!(runtime.elapsed(), Duration::from_secs(60));
+    }
+
+    #[test]
+    #[should_panic(expected = "stalled")]
+    fn block_on_panics_when_nothing_can_wake_it() {
+        let runtime = VirtualRuntime::new();
+        let (_sender, receiver) = async_channel::unbounded::<()>();
+        let _ = runtime.block_on(receiver.recv());
+    }
+
+    #[test]
+    fn silent_peer_times_out() {
+        let runtime = VirtualRuntime::new();
+        // Packets go nowhere, so the peer is never heard from.
+        let (packets, _unsent) = async_channel::unbounded();
+        let config = ConnectionConfig {
+            keepalive_interval: Duration::from_secs(1),
+            idle_timeout: Duration::from_secs(5),
+        };
+        let mut multiplexer =
+            ConnectionMultiplexer::<_, GameProtocol>::with_config(runtime.clone(), packets, config);
+        let events = multiplexer.subscribe();
+        let id = multiplexer.register();
+        let disconnected = runtime.block_on(async {
+            loop {
+                match events.recv().await.unwrap() {
+                    ConnectionEvent::Disconnected(id, reason) => break (id, reason),
+                    _ => continue,
+                }
+            }
+        });
+        assert_eq!(disconnected, (id, DisconnectReason::TimedOut));
+        assert!(runtime.elapsed() >= Duration::from_secs(5));
+        assert!(runtime.elapsed() < Duration::from_secs(6));
+    }
+
+    #[test]
+    fn lifecycle_events_arrive_in_order() {
+        let runtime = VirtualRuntime::new();
+        let mut network = LoopbackNetwork::new(runtime.clone(), LinkConditioner::perfect());
+        let mut server =
+            ConnectionMultiplexer::<_, GameProtocol>::new(runtime.clone(), network.server_sender());
+        let events = server.subscribe();
+        let drain = || std::iter::from_fn(|| events.try_recv().ok()).collect::<Vec<_>>();
+
+        // Nobody answers this one, so it never counts as connected.
+        let silent = server.register();
+        let mut client = network.connect(&mut server);
+        let id = client.server_id;
+        assert_eq!(
+            drain(),
+            vec![
+                ConnectionEvent::Registered(silent),
+                ConnectionEvent::Registered(id),
+            ]
+        );
+
+        // The client's first keepalive is the first thing the server hears from it.
+        runtime.advance(Duration::from_millis(500));
+        assert!(drain().is_empty());
+        runtime.advance(Duration::from_secs(2));
+        assert_eq!(drain(), vec![ConnectionEvent::Connected(id)]);
+
+        network.disconnect(&mut server, &mut client).unwrap();
+        runtime.advance(Duration::from_millis(100));
+        assert_eq!(
+            drain(),
+            vec![ConnectionEvent::Disconnected(id, DisconnectReason::Killed)]
+        );
+    }
+}
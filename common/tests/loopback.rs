@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use common::loopback::{LinkConditioner, LoopbackClient, LoopbackNetwork};
+use common::message::{GameMessage, GameProtocol, Message, ReliableMessage, Target};
+use common::multiplexer::ConnectionMultiplexer;
+use common::virtual_time::VirtualRuntime;
+
+const MESSAGES: usize = 100;
+
+struct Setup {
+    runtime: VirtualRuntime,
+    server: ConnectionMultiplexer<VirtualRuntime, GameProtocol>,
+    client: LoopbackClient<VirtualRuntime, GameProtocol>,
+}
+
+fn setup(conditioner: LinkConditioner) -> Setup {
+    let runtime = VirtualRuntime::new();
+    let mut network = LoopbackNetwork::new(runtime.clone(), conditioner);
+    let mut server = ConnectionMultiplexer::new(runtime.clone(), network.server_sender());
+    let client = network.connect(&mut server);
+    Setup {
+        runtime,
+        server,
+        client,
+    }
+}
+
+fn lossy() -> LinkConditioner {
+    LinkConditioner::perfect()
+        .with_latency(Duration::from_millis(40), Duration::from_millis(20))
+        .with_loss(0.2)
+        .with_duplicate(0.1)
+        .with_reorder(0.1)
+        .with_seed(7)
+}
+
+// Sends `MESSAGES` of each kind from the client and returns what the server got, in
+// the order it got them, after letting `time` pass.
+fn exchange(setup: &mut Setup, time: Duration) -> (Vec<String>, Vec<(f32, f32)>) {
+    let id = setup.client.client_id;
+    for i in 0..MESSAGES {
+        let multiplexer = &setup.client.multiplexer;
+        multiplexer
+            .send_message(Target::Client(id), ReliableMessage::Text(i.to_string()))
+            .unwrap();
+        multiplexer
+            .send_message(Target::Client(id), Message::Position(i as f32, 0.0))
+            .unwrap();
+        setup.runtime.advance(Duration::from_millis(10));
+    }
+    setup.runtime.advance(time);
+
+    let receiver = setup.server.message_receiver();
+    let mut reliable = Vec::new();
+    let mut unreliable = Vec::new();
+    while let Ok(message) = receiver.try_recv() {
+        assert_eq!(message.id, setup.client.server_id);
+        match message.message {
+            GameMessage::Reliable(ReliableMessage::Text(text)) => reliable.push(text),
+            GameMessage::Unreliable(Message::Position(x, y)) => unreliable.push((x, y)),
+            other => panic!("Unexpected message {:?}", other),
+        }
+    }
+    (reliable, unreliable)
+}
+
+fn expected_texts() -> Vec<String> {
+    (0..MESSAGES).map(|i| i.to_string()).collect()
+}
+
+#[test]
+fn perfect_link_delivers_everything_in_order() {
+    let mut setup = setup(LinkConditioner::perfect());
+    let (reliable, unreliable) = exchange(&mut setup, Duration::from_secs(1));
+    assert_eq!(reliable, expected_texts());
+    let expected: Vec<(f32, f32)> = (0..MESSAGES).map(|i| (i as f32, 0.0)).collect();
+    assert_eq!(unreliable, expected);
+}
+
+#[test]
+fn lossy_link_delivers_reliable_messages_in_order() {
+    let mut setup = setup(lossy());
+    let (reliable, _) = exchange(&mut setup, Duration::from_secs(10));
+    assert_eq!(reliable, expected_texts());
+}
+
+#[test]
+fn lossy_link_delivers_some_unreliable_messages() {
+    let mut setup = setup(lossy());
+    let (_, unreliable) = exchange(&mut setup, Duration::from_secs(10));
+    let mut sent: Vec<usize> = unreliable.iter().map(|(x, _)| *x as usize).collect();
+    assert!(sent.iter().all(|i| *i < MESSAGES));
+    sent.sort_unstable();
+    sent.dedup();
+    // Some get through but never all of them, unreliable messages aren't resent.
+    assert!(!sent.is_empty());
+    assert!(sent.len() < MESSAGES);
+}
@@ -0,0 +1,91 @@
+use common::event::{ConnectionEvent, DisconnectReason};
+use common::host::GameHost;
+use common::interest::Interest;
+use common::message::{ReliableMessage, SignedMessage};
+use common::session::ClientSession;
+use common::simulation::{Simulation, SPAWN_POSITION};
+
+const CLIENT: usize = 1;
+const MS_PER_TICK: f32 = 50.0;
+// Frame time of the client in milliseconds, one host tick runs per frame.
+const FRAME: f32 = 33.0;
+
+fn room(epoch: u32) -> GameHost {
+    let simulation = Simulation::new(MS_PER_TICK);
+    let mut host = GameHost::for_room(simulation, 7, Interest::default(), epoch);
+    host.handle_event(ConnectionEvent::Registered(CLIENT));
+    host.handle_event(ConnectionEvent::Connected(CLIENT));
+    host
+}
+
+fn joined(code: &str, epoch: u32) -> ReliableMessage {
+    ReliableMessage::JoinedRoom(code.to_string(), epoch)
+}
+
+fn send(session: &mut ClientSession, host: &mut GameHost) {
+    for message in session.outgoing() {
+        host.handle_message(SignedMessage {
+            id: CLIENT,
+            message,
+        });
+    }
+}
+
+// Samples a frame of input, has `host` tick on it and hands its replies back.
+fn frame(session: &mut ClientSession, host: &mut GameHost) {
+    session.update(FRAME);
+    send(session, host);
+    host.tick();
+    for message in host.outgoing() {
+        if message.id == CLIENT {
+            session.handle_message(message.message);
+        }
+    }
+}
+
+#[test]
+fn the_player_walks_where_it_clicked() {
+    let mut session = ClientSession::new();
+    let mut host = room(0);
+    session.handle_message(joined("abc", 0).into());
+    let target = (SPAWN_POSITION.0 + 30.0, SPAWN_POSITION.1);
+    session.click(target.0, target.1);
+    for _ in 0..60 {
+        frame(&mut session, &mut host);
+    }
+    assert_eq!(
+        host.simulation().player_position(CLIENT as u32),
+        Some(target)
+    );
+}
+
+#[test]
+fn the_player_still_moves_after_switching_rooms() {
+    let mut session = ClientSession::new();
+    let mut first = room(0);
+    session.handle_message(joined("abc", 0).into());
+    for _ in 0..300 {
+        frame(&mut session, &mut first);
+    }
+
+    // The server moves us over before we hear about it, so input meant for the old room
+    // reaches the new one.
+    first.handle_event(ConnectionEvent::Disconnected(
+        CLIENT,
+        DisconnectReason::Killed,
+    ));
+    let mut second = room(1);
+    session.update(FRAME);
+    send(&mut session, &mut second);
+    session.handle_message(joined("def", 1).into());
+
+    let target = (SPAWN_POSITION.0 + 30.0, SPAWN_POSITION.1);
+    session.click(target.0, target.1);
+    for _ in 0..60 {
+        frame(&mut session, &mut second);
+    }
+    assert_eq!(
+        second.simulation().player_position(CLIENT as u32),
+        Some(target)
+    );
+}
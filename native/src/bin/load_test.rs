@@ -0,0 +1,401 @@
+// Points a crowd of simulated players at a server's UDP transport and reports how it
+// held up. Every bot clicks around and every now and then leaves and comes back, at
+// rates set through the environment variables below. Bots don't chat, rooms don't pass
+// chat on to anyone, so it would be load nobody sees.
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use common::simulation::WORLD_SIZE;
+use common::stats::{ConnectionStats, TickStats};
+use native::{Client, SnapshotArrival};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use tracing::Level;
+
+// Overridden with the SERVER, CLIENTS, DURATION and RAMP_UP environment variables, the
+// last two in seconds. Connecting is spread out over the ramp up.
+const DEFAULT_SERVER: &str = "127.0.0.1:42425";
+const DEFAULT_CLIENTS: usize = 100;
+const DEFAULT_DURATION: u64 = 60;
+const DEFAULT_RAMP_UP: u64 = 10;
+// Overridden with CLICK_RATE and CHURN_RATE, how often every bot does each per
+// second. Bots join the room in ROOM if it's set and let the server match them
+// otherwise.
+const DEFAULT_CLICK_RATE: f64 = 1.0;
+const DEFAULT_CHURN_RATE: f64 = 0.01;
+
+// Bots update at about the rate a browser renders.
+const FRAME: Duration = Duration::from_millis(33);
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+const REJOIN_DELAY: Duration = Duration::from_millis(500);
+
+struct Config {
+    server: SocketAddr,
+    clients: usize,
+    duration: Duration,
+    ramp_up: Duration,
+    click_rate: f64,
+    churn_rate: f64,
+    room: Option<String>,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        Self {
+            server: env_or("SERVER", DEFAULT_SERVER.parse().unwrap()),
+            clients: env_or("CLIENTS", DEFAULT_CLIENTS),
+            duration: Duration::from_secs(env_or("DURATION", DEFAULT_DURATION)),
+            ramp_up: Duration::from_secs(env_or("RAMP_UP", DEFAULT_RAMP_UP)),
+            click_rate: env_or("CLICK_RATE", DEFAULT_CLICK_RATE),
+            churn_rate: env_or("CHURN_RATE", DEFAULT_CHURN_RATE),
+            room: std::env::var("ROOM").ok(),
+        }
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+// What the bots measured. Every bot keeps its own and they're merged at the end.
+#[derive(Default)]
+struct Report {
+    sessions: u64,
+    failed_connects: u64,
+    // Sessions that ended without the bot leaving.
+    dropped: u64,
+    clicks: u64,
+    rtt: Vec<f64>,
+    packet_loss: Vec<f64>,
+    snapshots: u64,
+    // Snapshots there would have been if none were lost or skipped.
+    expected_snapshots: u64,
+    // Server milliseconds per tick between consecutive snapshots.
+    tick_interval: Vec<f64>,
+    // What the rooms said about their tick times, by room epoch and last tick covered.
+    // Every bot in a room hears the same reports, this counts each once.
+    server_ticks: HashMap<(u32, u64), TickStats>,
+    // Our milliseconds between consecutive snapshots arriving.
+    snapshot_gap: Vec<f64>,
+    bytes_in: u64,
+    bytes_out: u64,
+    packets_in: u64,
+    packets_out: u64,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.sessions += other.sessions;
+        self.failed_connects += other.failed_connects;
+        self.dropped += other.dropped;
+        self.clicks += other.clicks;
+        self.rtt.extend(other.rtt);
+        self.packet_loss.extend(other.packet_loss);
+        self.snapshots += other.snapshots;
+        self.expected_snapshots += other.expected_snapshots;
+        self.tick_interval.extend(other.tick_interval);
+        self.server_ticks.extend(other.server_ticks);
+        self.snapshot_gap.extend(other.snapshot_gap);
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.packets_in += other.packets_in;
+        self.packets_out += other.packets_out;
+    }
+
+    // Counters of a connection that is done with.
+    fn add_totals(&mut self, stats: &ConnectionStats) {
+        self.bytes_in += stats.bytes_in;
+        self.bytes_out += stats.bytes_out;
+        self.packets_in += stats.packets_in;
+        self.packets_out += stats.packets_out;
+    }
+
+    fn print(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        println!();
+        println!("== Load test report after {:.1}s", seconds);
+        println!(
+            "Sessions: {} started, {} dropped by the server, {} failed to connect",
+            self.sessions, self.dropped, self.failed_connects
+        );
+        println!("Clicks: {} (chat isn't simulated)", self.clicks);
+        println!("Connection RTT (ms): {}", percentiles(&mut self.rtt));
+        let loss = mean(&self.packet_loss) * 100.0;
+        println!("Packet loss (estimated from pings): {:.2}%", loss);
+        println!(
+            "Snapshots: {} received, {:.2}% lost or skipped",
+            self.snapshots,
+            percent(
+                self.expected_snapshots - self.snapshots,
+                self.expected_snapshots
+            )
+        );
+        println!(
+            "Server tick interval (ms): {}",
+            percentiles(&mut self.tick_interval)
+        );
+        self.print_server_ticks();
+        println!(
+            "Snapshot arrival gap (ms): {}",
+            percentiles(&mut self.snapshot_gap)
+        );
+        println!(
+            "Throughput: {:.1} KB/s in, {:.1} KB/s out, {:.0} packets/s in, {:.0} packets/s out",
+            self.bytes_in as f64 / 1024.0 / seconds,
+            self.bytes_out as f64 / 1024.0 / seconds,
+            self.packets_in as f64 / seconds,
+            self.packets_out as f64 / seconds,
+        );
+    }
+}
+
+impl Report {
+    fn print_server_ticks(&self) {
+        let reports: Vec<&TickStats> = self.server_ticks.values().collect();
+        if reports.is_empty() {
+            println!("Server tick time (ms): no reports");
+            return;
+        }
+        let ticks: u64 = reports.iter().map(|stats| stats.ticks as u64).sum();
+        let total: f64 = reports
+            .iter()
+            .map(|stats| stats.mean_ms as f64 * stats.ticks as f64)
+            .sum();
+        let mut slowest: Vec<f64> = reports.iter().map(|stats| stats.max_ms as f64).collect();
+        let overruns: u64 = reports.iter().map(|stats| stats.overruns as u64).sum();
+        let skipped: u64 = reports.iter().map(|stats| stats.skipped as u64).sum();
+        println!(
+            "Server tick time (ms): mean {:.2} of a {:.1} budget over {} ticks",
+            total / ticks.max(1) as f64,
+            reports[0].budget_ms,
+            ticks
+        );
+        println!(
+            "Slowest server tick per report (ms): {}",
+            percentiles(&mut slowest)
+        );
+        println!(
+            "Server ticks: {} overran ({:.2}%), {} skipped",
+            overruns,
+            percent(overruns, ticks),
+            skipped
+        );
+    }
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        return 0.0;
+    }
+    part as f64 / whole as f64 * 100.0
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn percentiles(values: &mut [f64]) -> String {
+    if values.is_empty() {
+        return "no samples".to_string();
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let at = |p: f64| values[((values.len() - 1) as f64 * p).round() as usize];
+    format!(
+        "p50 {:.1}, p90 {:.1}, p99 {:.1}, max {:.1} ({} samples)",
+        at(0.5),
+        at(0.9),
+        at(0.99),
+        values[values.len() - 1],
+        values.len()
+    )
+}
+
+// Why a session ended.
+enum Ending {
+    Deadline,
+    Churn,
+    Dropped,
+}
+
+struct Bot {
+    index: usize,
+    config: Arc<Config>,
+    connected: Arc<AtomicUsize>,
+    rng: SmallRng,
+    report: Report,
+}
+
+impl Bot {
+    fn new(index: usize, config: Arc<Config>, connected: Arc<AtomicUsize>) -> Self {
+        Self {
+            index,
+            config,
+            connected,
+            rng: SmallRng::from_entropy(),
+            report: Report::default(),
+        }
+    }
+
+    async fn run(mut self, deadline: Instant) -> Report {
+        let mut client = Client::new();
+        while Instant::now() < deadline {
+            if let Err(err) = client
+                .connect_to_room(self.config.server, self.config.room.clone())
+                .await
+            {
+                tracing::warn!("Bot {} failed to connect: {}", self.index, err);
+                self.report.failed_connects += 1;
+                tokio::time::sleep(REJOIN_DELAY).await;
+                continue;
+            }
+            self.report.sessions += 1;
+            self.connected.fetch_add(1, Ordering::Relaxed);
+            let ending = self.session(&mut client, deadline).await;
+            self.connected.fetch_sub(1, Ordering::Relaxed);
+            match ending {
+                Ending::Deadline => break,
+                Ending::Churn => tokio::time::sleep(REJOIN_DELAY).await,
+                Ending::Dropped => self.report.dropped += 1,
+            }
+        }
+        self.report
+    }
+
+    // Plays until the deadline, a churn or the server dropping us, then disconnects.
+    async fn session(&mut self, client: &mut Client, deadline: Instant) -> Ending {
+        let mut last_arrival: Option<SnapshotArrival> = None;
+        let mut stats = None;
+        let mut last_stats = Instant::now();
+        let mut last_frame = Instant::now();
+        let ending = loop {
+            tokio::time::sleep(FRAME).await;
+            let now = Instant::now();
+            let dt = now - last_frame;
+            last_frame = now;
+
+            client.update(dt.as_secs_f32() * 1000.0);
+            // Click reports pile up otherwise.
+            client.pending_messages();
+            for arrival in client.take_arrivals() {
+                self.record_arrival(last_arrival, arrival);
+                last_arrival = Some(arrival);
+            }
+            for stats in client.take_tick_stats() {
+                self.report
+                    .server_ticks
+                    .insert((stats.epoch, stats.tick), stats);
+            }
+            if last_stats.elapsed() >= STATS_INTERVAL {
+                last_stats = now;
+                if let Some(sample) = client.stats() {
+                    self.report.rtt.extend(sample.rtt_ms);
+                    self.report.packet_loss.push(sample.packet_loss);
+                    stats = Some(sample);
+                }
+            }
+            if !client.is_connected() {
+                break Ending::Dropped;
+            }
+
+            if now >= deadline {
+                break Ending::Deadline;
+            }
+            let seconds = dt.as_secs_f64();
+            if self.happens(self.config.churn_rate, seconds) {
+                break Ending::Churn;
+            }
+            if self.happens(self.config.click_rate, seconds) {
+                let x = self.rng.gen_range(0.0, WORLD_SIZE.0);
+                let y = self.rng.gen_range(0.0, WORLD_SIZE.1);
+                client.click(x, y);
+                self.report.clicks += 1;
+            }
+        };
+        if let Some(stats) = client.stats().or(stats) {
+            self.report.add_totals(&stats);
+        }
+        client.disconnect();
+        ending
+    }
+
+    // Whether something that happens `rate` times a second happened in `seconds`.
+    fn happens(&mut self, rate: f64, seconds: f64) -> bool {
+        self.rng.gen_bool((rate * seconds).clamp(0.0, 1.0))
+    }
+
+    fn record_arrival(&mut self, last: Option<SnapshotArrival>, arrival: SnapshotArrival) {
+        self.report.snapshots += 1;
+        match last {
+            // Ticks start over when we change rooms.
+            Some(last) if arrival.tick > last.tick => {
+                let ticks = arrival.tick - last.tick;
+                self.report.expected_snapshots += ticks;
+                self.report
+                    .tick_interval
+                    .push((arrival.server_time - last.server_time) / ticks as f64);
+                self.report
+                    .snapshot_gap
+                    .push(arrival.received - last.received);
+            }
+            _ => self.report.expected_snapshots += 1,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt().with_max_level(Level::WARN).init();
+
+    let config = Arc::new(Config::from_env());
+    println!(
+        "Running {} clients against {} for {}s",
+        config.clients,
+        config.server,
+        config.duration.as_secs()
+    );
+    let started = Instant::now();
+    let deadline = started + config.duration;
+    let connected = Arc::new(AtomicUsize::new(0));
+
+    let progress_connected = connected.clone();
+    tokio::spawn(async move {
+        while Instant::now() < deadline {
+            tokio::time::sleep(PROGRESS_INTERVAL).await;
+            println!(
+                "{:>4}s: {} clients connected",
+                started.elapsed().as_secs(),
+                progress_connected.load(Ordering::Relaxed)
+            );
+        }
+    });
+
+    let spawn_interval = config.ramp_up / config.clients.max(1) as u32;
+    let mut bots = Vec::with_capacity(config.clients);
+    for index in 0..config.clients {
+        let bot = Bot::new(index, config.clone(), connected.clone());
+        bots.push(tokio::spawn(bot.run(deadline)));
+        tokio::time::sleep(spawn_interval).await;
+    }
+
+    let mut report = Report::default();
+    for bot in bots {
+        match bot.await {
+            Ok(bot_report) => report.merge(bot_report),
+            Err(err) => tracing::error!("Bot crashed: {}", err),
+        }
+    }
+    report.print(started.elapsed());
+}
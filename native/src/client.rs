@@ -0,0 +1,327 @@
+use std::{collections::VecDeque, fmt, net::SocketAddr, time::Duration};
+
+use async_channel::{Receiver, Sender};
+use common::event::{ConnectionEvent, DisconnectReason};
+use common::message::{
+    GameMessage, GameProtocol, InternalMessage, Message, RawMessage, SignedMessage, Target,
+};
+use common::multiplexer::ConnectionMultiplexer;
+use common::runtime::NativeRuntime;
+use common::session::{ClientSession, SessionEvent};
+use common::snapshot::{StateChanges, WorldState};
+use common::stats::{ConnectionStats, TickStats};
+use futures::{pin_mut, select, FutureExt};
+
+use crate::udp::UdpConnection;
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+// How many snapshot arrivals and tick stats are kept for `take_arrivals` and
+// `take_tick_stats`, older ones are dropped.
+const ARRIVAL_LOG_LEN: usize = 256;
+
+#[derive(Debug)]
+pub enum ClientError {
+    AlreadyConnected,
+    // The server never answered our hellos.
+    HandshakeTimedOut,
+    Io(std::io::Error),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::AlreadyConnected => write!(f, "already connected, disconnect first"),
+            ClientError::HandshakeTimedOut => write!(f, "the server didn't answer"),
+            ClientError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<std::io::Error> for ClientError {
+    fn from(err: std::io::Error) -> Self {
+        ClientError::Io(err)
+    }
+}
+
+// When a snapshot came in, for keeping an eye on how regularly the server ticks.
+#[derive(Debug, Clone, Copy)]
+pub struct SnapshotArrival {
+    pub tick: u64,
+    // Server clock when the tick was simulated, in milliseconds.
+    pub server_time: f64,
+    // Our clock when the snapshot was applied, in milliseconds.
+    pub received: f64,
+}
+
+struct Connection {
+    channel_number: usize,
+    // Closed to stop the connection's tasks, and by them when the socket gives out.
+    internal_tx: Sender<InternalMessage>,
+}
+
+// The wasm `Processor` for native code. Talks to a server over its UDP transport and
+// drives the same `ClientSession`, so it keeps the same view of the world: the latest
+// snapshot, our predicted player and the texts we were sent. Everything but `connect`
+// is synchronous, incoming messages are handled whenever the state is looked at, so
+// callers drive it from their own loop.
+pub struct Client {
+    connection: Option<Connection>,
+    multiplexer: ConnectionMultiplexer<NativeRuntime, GameProtocol>,
+
+    message_receiver: Receiver<SignedMessage<GameMessage>>,
+    connection_events: Receiver<ConnectionEvent>,
+    signed_packet_receiver: Receiver<SignedMessage<RawMessage>>,
+
+    session: ClientSession,
+    arrivals: VecDeque<SnapshotArrival>,
+    tick_stats: VecDeque<TickStats>,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Client {
+    pub fn new() -> Self {
+        let (signed_packet_sender, signed_packet_receiver) = async_channel::unbounded();
+        let multiplexer = ConnectionMultiplexer::new(NativeRuntime::new(), signed_packet_sender);
+        let message_receiver = multiplexer.message_receiver();
+        let connection_events = multiplexer.subscribe();
+        Self {
+            connection: None,
+            multiplexer,
+            message_receiver,
+            connection_events,
+            signed_packet_receiver,
+            session: ClientSession::new(),
+            arrivals: VecDeque::new(),
+            tick_stats: VecDeque::new(),
+        }
+    }
+
+    // Connects and lets the server pick a room.
+    pub async fn connect(&mut self, address: SocketAddr) -> Result<(), ClientError> {
+        self.connect_to_room(address, None).await
+    }
+
+    // Connects and asks for the room with code `room`, or a match without one.
+    pub async fn connect_to_room(
+        &mut self,
+        address: SocketAddr,
+        room: Option<String>,
+    ) -> Result<(), ClientError> {
+        if self.is_connected() {
+            return Err(ClientError::AlreadyConnected);
+        }
+        let socket = UdpConnection::connect(address).await?;
+
+        let channel_number = self.multiplexer.register();
+        self.session.reset();
+        // Anything queued while we weren't connected was meant for an older session.
+        self.session.outgoing();
+        let (internal_tx, internal_rx) = async_channel::unbounded();
+        let incoming = self
+            .multiplexer
+            .get_raw_channel(channel_number)
+            .expect("connection was just registered");
+        tokio::spawn(socket.run(
+            channel_number,
+            self.signed_packet_receiver.clone(),
+            incoming,
+            internal_rx.clone(),
+        ));
+
+        let message_sender = self
+            .multiplexer
+            .get_message_channel(channel_number)
+            .expect("connection was just registered");
+        let clock = self.session.clock();
+        tokio::spawn(async move {
+            let ping = async move {
+                loop {
+                    if message_sender
+                        .send(Message::Sync(clock.now()).into())
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                    tokio::time::sleep(PING_INTERVAL).await;
+                }
+            }
+            .fuse();
+            let terminate = async move {
+                let _ = internal_rx.recv().await;
+            }
+            .fuse();
+            pin_mut!(ping, terminate);
+            select! {
+                () = ping => {},
+                () = terminate => {},
+            };
+        });
+
+        self.connection = Some(Connection {
+            channel_number,
+            internal_tx,
+        });
+        self.session.request_room(room);
+        self.flush();
+        Ok(())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    pub fn disconnect(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            if let Err(err) = self.multiplexer.kill(connection.channel_number) {
+                tracing::info!("Failed to kill connection: {}", err);
+            }
+            connection.internal_tx.close();
+        }
+        self.session.disconnected();
+    }
+
+    pub fn process_pending(&mut self) {
+        if matches!(&self.connection, Some(connection) if connection.internal_tx.is_closed()) {
+            tracing::info!("Lost connection to server");
+            self.disconnect();
+        }
+        while let Ok(event) = self.connection_events.try_recv() {
+            let current = self
+                .connection
+                .as_ref()
+                .map(|connection| connection.channel_number);
+            if current != Some(event.connection()) {
+                continue;
+            }
+            match event {
+                ConnectionEvent::Disconnected(_, DisconnectReason::Killed) => {}
+                ConnectionEvent::Disconnected(_, _) | ConnectionEvent::ProcessorCrashed(_, _) => {
+                    tracing::info!("Lost connection to server: {:?}", event);
+                    self.disconnect();
+                }
+                _ => {}
+            }
+        }
+        while let Ok(message) = self.message_receiver.try_recv() {
+            match self.session.handle_message(message.message) {
+                Some(SessionEvent::Snapshot { tick, time }) => {
+                    if self.arrivals.len() == ARRIVAL_LOG_LEN {
+                        self.arrivals.pop_front();
+                    }
+                    self.arrivals.push_back(SnapshotArrival {
+                        tick,
+                        server_time: time,
+                        received: self.session.clock().now(),
+                    });
+                }
+                Some(SessionEvent::TickStats(stats)) => {
+                    if self.tick_stats.len() == ARRIVAL_LOG_LEN {
+                        self.tick_stats.pop_front();
+                    }
+                    self.tick_stats.push_back(stats);
+                }
+                Some(SessionEvent::JoinedRoom(_)) | None => {}
+            }
+        }
+        self.flush();
+    }
+
+    // Texts received since the last call.
+    pub fn pending_messages(&mut self) -> Vec<String> {
+        self.process_pending();
+        self.session.take_messages()
+    }
+
+    // The latest world state, with our own player where prediction puts it.
+    pub fn state(&mut self) -> WorldState {
+        self.process_pending();
+        self.session.predicted(self.session.state().clone())
+    }
+
+    // Objects that were added, changed or removed since the last call.
+    pub fn take_changes(&mut self) -> StateChanges {
+        self.process_pending();
+        self.session.take_changes()
+    }
+
+    // Snapshots applied since the last call, oldest first.
+    pub fn take_arrivals(&mut self) -> Vec<SnapshotArrival> {
+        self.process_pending();
+        self.arrivals.drain(..).collect()
+    }
+
+    // What the server said about its tick times since the last call, oldest first.
+    pub fn take_tick_stats(&mut self) -> Vec<TickStats> {
+        self.process_pending();
+        self.tick_stats.drain(..).collect()
+    }
+
+    // Samples input for a frame that took `dt` milliseconds, applies it locally and
+    // sends it to the server.
+    pub fn update(&mut self, dt: f32) {
+        if !self.is_connected() {
+            return;
+        }
+        self.session.update(dt);
+        self.flush();
+    }
+
+    pub fn click(&mut self, x: f32, y: f32) {
+        self.session.click(x, y);
+        self.flush();
+    }
+
+    pub fn send(&mut self, text: String) {
+        self.session.send_text(text);
+        self.flush();
+    }
+
+    // The server's clock in milliseconds, once the first sync finished.
+    pub fn server_time(&self) -> Option<f64> {
+        self.session.server_time()
+    }
+
+    pub fn stats(&self) -> Option<ConnectionStats> {
+        let connection = self.connection.as_ref()?;
+        self.multiplexer.stats(connection.channel_number).ok()
+    }
+
+    // Code of the room we're in, once the server has put us in one.
+    pub fn room(&self) -> Option<&str> {
+        self.session.room()
+    }
+
+    // Moves to another room on the same server.
+    pub fn join_room(&mut self, code: String) {
+        self.session.request_room(Some(code));
+        self.flush();
+    }
+
+    // Sends whatever the session queued up.
+    fn flush(&mut self) {
+        let outgoing = self.session.outgoing();
+        let connection = match self.connection.as_ref() {
+            Some(connection) => connection,
+            None => {
+                if !outgoing.is_empty() {
+                    tracing::info!("Tried to send message but no connection exists!");
+                }
+                return;
+            }
+        };
+        for message in outgoing {
+            let target = Target::Client(connection.channel_number);
+            if let Err(err) = self.multiplexer.send_message(target, message) {
+                tracing::info!("Failed to send message: {}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,8 @@
+// A game client for everything that isn't a browser: bots, load tests and scripted
+// checks against a running server. It speaks the same protocol as the wasm client,
+// over the server's plain UDP transport.
+mod client;
+mod udp;
+
+pub use client::{Client, ClientError, SnapshotArrival};
+pub use common::runtime::NativeRuntime;
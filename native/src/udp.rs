@@ -0,0 +1,108 @@
+use std::{net::SocketAddr, time::Duration};
+
+use async_channel::{Receiver, Sender};
+use common::message::{InternalMessage, RawMessage, SignedMessage};
+use common::udp::{Datagram, MAX_DATAGRAM_LEN};
+use futures::{pin_mut, select, FutureExt};
+use tokio::net::UdpSocket;
+
+use crate::client::ClientError;
+
+// Hellos are resent this often until the server welcomes us, for at most
+// `HANDSHAKE_ATTEMPTS` tries.
+const HELLO_INTERVAL: Duration = Duration::from_millis(250);
+const HANDSHAKE_ATTEMPTS: usize = 20;
+
+// A socket that completed the handshake with the server, framed as described in
+// `common::udp`.
+pub struct UdpConnection {
+    socket: UdpSocket,
+}
+
+impl UdpConnection {
+    pub async fn connect(address: SocketAddr) -> Result<Self, ClientError> {
+        let local: SocketAddr = if address.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        // Same std-then-tokio dance as the server's `UdpTransport`.
+        let socket = std::net::UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket)?;
+        socket.connect(address).await?;
+        let hello = Datagram::Hello.encode();
+        let mut buffer = [0; 16];
+        for _ in 0..HANDSHAKE_ATTEMPTS {
+            socket.send(&hello).await?;
+            match tokio::time::timeout(HELLO_INTERVAL, socket.recv(&mut buffer)).await {
+                Ok(Ok(len)) if Datagram::decode(&buffer[..len]) == Some(Datagram::Welcome) => {
+                    tracing::info!("Connected to {}", address);
+                    return Ok(Self { socket });
+                }
+                Ok(Err(err)) => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Err(ClientError::HandshakeTimedOut)
+    }
+
+    // Moves packets between the socket and the multiplexer connection `channel` until
+    // `shutdown` is closed or the socket fails. Says bye on the way out and closes
+    // `shutdown` itself, so the client can tell the connection is gone.
+    pub async fn run(
+        self,
+        channel: usize,
+        outgoing: Receiver<SignedMessage<RawMessage>>,
+        incoming: Sender<RawMessage>,
+        shutdown: Receiver<InternalMessage>,
+    ) {
+        let socket = &self.socket;
+        let receive = async move {
+            let mut buffer = vec![0; MAX_DATAGRAM_LEN];
+            loop {
+                let len = match socket.recv(&mut buffer).await {
+                    Ok(len) => len,
+                    Err(err) => {
+                        tracing::info!("Lost connection to server: {}", err);
+                        return;
+                    }
+                };
+                if let Some(Datagram::Data(payload)) = Datagram::decode(&buffer[..len]) {
+                    if incoming.send(payload.into()).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        .fuse();
+        let dispatch = async move {
+            while let Ok(packet) = outgoing.recv().await {
+                // Leftovers from an earlier connection.
+                if packet.id != channel {
+                    continue;
+                }
+                if let Err(err) = socket.send(&Datagram::Data(&packet.message).encode()).await {
+                    tracing::info!("Failed to send to server: {}", err);
+                    return;
+                }
+            }
+        }
+        .fuse();
+        let inner_shutdown = shutdown.clone();
+        let terminate = async move {
+            let _ = inner_shutdown.recv().await;
+        }
+        .fuse();
+        pin_mut!(receive, dispatch, terminate);
+        select! {
+            () = receive => {},
+            () = dispatch => {},
+            () = terminate => {},
+        };
+        shutdown.close();
+        if let Err(err) = self.socket.send(&Datagram::Bye.encode()).await {
+            tracing::info!("Failed to say bye: {}", err);
+        }
+    }
+}
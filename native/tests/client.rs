@@ -0,0 +1,165 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use common::event::ConnectionEvent;
+use common::host::GameHost;
+use common::message::{GameMessage, GameProtocol, RawMessage, SignedMessage, Target};
+use common::multiplexer::ConnectionMultiplexer;
+use common::simulation::{Simulation, SPAWN_POSITION};
+use common::udp::{Datagram, MAX_DATAGRAM_LEN};
+use futures::{pin_mut, select, FutureExt};
+use native::{Client, NativeRuntime};
+use tokio::net::UdpSocket;
+
+const TICK: Duration = Duration::from_millis(50);
+const FRAME: Duration = Duration::from_millis(16);
+const TIMEOUT: Duration = Duration::from_secs(10);
+// The first connection the host's multiplexer registers, players share its id.
+const PLAYER: u32 = 1;
+
+// What the server's UDP transport and packet loop do, cut down to one `GameHost` and
+// no rooms, so a `Client` has something real to play against.
+struct TestServer {
+    address: SocketAddr,
+    host: Arc<Mutex<GameHost>>,
+}
+
+enum Action {
+    Datagram(usize, SocketAddr),
+    Send(SignedMessage<RawMessage>),
+    Receive(SignedMessage<GameMessage>),
+    Event(ConnectionEvent),
+    Tick,
+}
+
+impl TestServer {
+    async fn start() -> Self {
+        // Same std-then-tokio dance as the server's `UdpTransport`.
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let socket = UdpSocket::from_std(socket).unwrap();
+        let address = socket.local_addr().unwrap();
+        let simulation = Simulation::new(TICK.as_secs_f32() * 1000.0);
+        let host = Arc::new(Mutex::new(GameHost::new(simulation, 7)));
+        tokio::spawn(serve(socket, host.clone()));
+        Self { address, host }
+    }
+
+    fn player_position(&self) -> Option<(f32, f32)> {
+        self.host
+            .lock()
+            .unwrap()
+            .simulation()
+            .player_position(PLAYER)
+    }
+}
+
+async fn serve(socket: UdpSocket, host: Arc<Mutex<GameHost>>) {
+    let (packets, outgoing) = async_channel::unbounded();
+    let mut multiplexer =
+        ConnectionMultiplexer::<_, GameProtocol>::new(NativeRuntime::new(), packets);
+    let messages = multiplexer.message_receiver();
+    let events = multiplexer.subscribe();
+    let mut peers: HashMap<SocketAddr, usize> = HashMap::new();
+    let mut addresses: HashMap<usize, SocketAddr> = HashMap::new();
+    let mut buffer = vec![0; MAX_DATAGRAM_LEN];
+    let mut ticks = tokio::time::interval(TICK);
+    loop {
+        let action = {
+            let received = socket.recv_from(&mut buffer).fuse();
+            let packet = outgoing.recv().fuse();
+            let message = messages.recv().fuse();
+            let event = events.recv().fuse();
+            let tick = ticks.tick().fuse();
+            pin_mut!(received, packet, message, event, tick);
+            select! {
+                received = received => {
+                    let (len, from) = received.unwrap();
+                    Action::Datagram(len, from)
+                },
+                packet = packet => Action::Send(packet.unwrap()),
+                message = message => Action::Receive(message.unwrap()),
+                event = event => Action::Event(event.unwrap()),
+                _ = tick => Action::Tick,
+            }
+        };
+        match action {
+            Action::Datagram(len, from) => match Datagram::decode(&buffer[..len]) {
+                Some(Datagram::Hello) => {
+                    socket
+                        .send_to(&Datagram::Welcome.encode(), from)
+                        .await
+                        .unwrap();
+                    if let Entry::Vacant(entry) = peers.entry(from) {
+                        let id = multiplexer.register();
+                        entry.insert(id);
+                        addresses.insert(id, from);
+                    }
+                }
+                Some(Datagram::Data(payload)) => {
+                    if let Some(id) = peers.get(&from) {
+                        let _ = multiplexer.send_raw(Target::Client(*id), payload.into());
+                    }
+                }
+                Some(Datagram::Bye) | Some(Datagram::Welcome) | None => {}
+            },
+            Action::Send(packet) => {
+                if let Some(address) = addresses.get(&packet.id) {
+                    let datagram = Datagram::Data(&packet.message).encode();
+                    socket.send_to(&datagram, address).await.unwrap();
+                }
+            }
+            Action::Receive(message) => host.lock().unwrap().handle_message(message),
+            Action::Event(event) => host.lock().unwrap().handle_event(event),
+            Action::Tick => {
+                let messages = {
+                    let mut host = host.lock().unwrap();
+                    host.tick();
+                    host.outgoing()
+                };
+                for message in messages {
+                    let _ = multiplexer.send_message(Target::Client(message.id), message.message);
+                }
+            }
+        }
+    }
+}
+
+// Plays frames until `done` says so, or gives up after `TIMEOUT`. Returns whether it
+// was done.
+async fn play_until(client: &mut Client, mut done: impl FnMut(&mut Client) -> bool) -> bool {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut last_frame = Instant::now();
+    while Instant::now() < deadline {
+        tokio::time::sleep(FRAME).await;
+        let now = Instant::now();
+        client.update((now - last_frame).as_secs_f32() * 1000.0);
+        last_frame = now;
+        if done(client) {
+            return true;
+        }
+    }
+    false
+}
+
+#[tokio::test]
+async fn clicking_walks_the_player_there() {
+    let server = TestServer::start().await;
+    let mut client = Client::new();
+    client.connect(server.address).await.unwrap();
+
+    // Snapshots start once the host has heard from us and added our player.
+    let joined = play_until(&mut client, |client| !client.take_arrivals().is_empty()).await;
+    assert!(joined);
+    assert_eq!(server.player_position(), Some(SPAWN_POSITION));
+
+    let target = (SPAWN_POSITION.0 + 30.0, SPAWN_POSITION.1);
+    client.click(target.0, target.1);
+    let arrived = play_until(&mut client, |_| server.player_position() == Some(target)).await;
+    assert!(arrived, "player stopped at {:?}", server.player_position());
+    client.disconnect();
+}
@@ -1,19 +1,21 @@
 use std::{
-    collections::{HashMap, HashSet},
     net::SocketAddr,
     time::{Duration, Instant},
 };
 
 use async_channel::{Receiver, Sender};
-use common::message::{
-    GameState, Message, Object, ObjectInfo, ReliableMessage, SignedMessage, Tree,
-};
+use common::event::ConnectionEvent;
+use common::host::GameHost;
+use common::interest::Interest;
+use common::message::{GameMessage, GameState, SignedMessage};
+use common::simulation::Simulation;
 use rand::Rng;
 
-use crate::InternalMessage;
-
 type FP = f32;
-const MS_PER_UPDATE: FP = 0.5;
+// When the loop falls further behind than this it drops ticks instead of trying to
+// catch up, otherwise one slow tick snowballs into every following one being late.
+const MAX_CATCHUP_TICKS: u32 = 5;
+const TREE_COUNT: usize = 20;
 
 #[derive(Debug)]
 pub struct TimeStep {
@@ -60,206 +62,76 @@ impl TimeStep {
 }
 
 pub struct ChannelBundle {
-    pub reliable_sender: Sender<SignedMessage<ReliableMessage>>,
-    pub reliable_receiver: Receiver<SignedMessage<ReliableMessage>>,
-    pub message_sender: Sender<SignedMessage<Message>>,
-    pub message_receiver: Receiver<SignedMessage<Message>>,
-    pub internal_receiver: Receiver<InternalMessage>,
-}
-
-#[derive(PartialEq)]
-pub enum ConnectionState {
-    Connecting,
-    Connected,
-    Disconnected,
+    pub sender: Sender<SignedMessage<GameMessage>>,
+    pub receiver: Receiver<SignedMessage<GameMessage>>,
+    pub events: Receiver<ConnectionEvent>,
 }
 
 pub struct Universe {
     bundle: ChannelBundle,
     timestep: TimeStep,
-    state: HashMap<u32, Object>,
-    players: HashMap<u32, Player>,
-    connected_clients: HashMap<usize, ConnectionState>,
-}
-
-struct Player {
-    position: (f32, f32)
+    host: GameHost,
+    ms_per_tick: FP,
+    accumulator: FP,
 }
 
 impl Universe {
-    fn make_tree(id: u32) -> Object {
+    pub fn new(bundle: ChannelBundle, tick_rate: u32, interest: Interest, epoch: u32) -> Self {
         let mut rng = rand::thread_rng();
-        Object {
-            id,
-            object_info: ObjectInfo::Tree(Tree {
-                position: (rng.gen_range(0.0, 500.0), rng.gen_range(0.0, 400.0)),
-                size: rng.gen_range(3.0, 10.0),
-            }),
-        }
-    }
-
-    pub fn refresh_clients(&mut self, clients: HashSet<usize>) {
-        self.connected_clients.iter_mut().for_each(|x| {
-            if !clients.contains(x.0) {
-                *x.1 = ConnectionState::Disconnected;
-            }
-        });
-
-        for client in clients {
-            if !self.connected_clients.contains_key(&client) {
-                self.connected_clients
-                    .insert(client, ConnectionState::Connecting);
-            }
-        }
-    }
-
-    pub fn new(bundle: ChannelBundle) -> Self {
-        let mut state = HashMap::new();
-        for i in 0..20 {
-            state.insert(i, Universe::make_tree(i));
-        }
+        let ms_per_tick = 1000.0 / tick_rate as FP;
+        let mut simulation = Simulation::new(ms_per_tick);
+        simulation.spawn_trees(TREE_COUNT, &mut rng);
         Self {
             bundle,
             timestep: TimeStep::new(),
-            connected_clients: HashMap::new(),
-            players: HashMap::new(),
-            state,
+            host: GameHost::for_room(simulation, rng.gen(), interest, epoch),
+            ms_per_tick,
+            accumulator: 0.0,
         }
     }
 
-    async fn send_game_state(&mut self, target: usize) {
-        tracing::info!("Sending game state {:?}", target);
-        self.bundle
-            .reliable_sender
-            .send(SignedMessage {
-                id: target,
-                message: ReliableMessage::State(self.state.clone()),
-            })
-            .await
-            .unwrap();
-    }
-
-    async fn client_tick(&mut self) {
-        if let Ok(message) = self.bundle.internal_receiver.try_recv() {
-            match message {
-                InternalMessage::ClientSnapshot(clients) => {
-                    tracing::info!("Received client snapshot: {:?}", clients);
-                    self.refresh_clients(clients);
-                    let disconnected_clients: Vec<usize> = self
-                        .connected_clients
-                        .iter()
-                        .filter(|&(_, state)| *state == ConnectionState::Disconnected)
-                        .map(|(addr, _)| *addr)
-                        .collect();
-                    let new_clients: Vec<usize> = self
-                        .connected_clients
-                        .iter()
-                        .filter(|&(_, state)| *state == ConnectionState::Connecting)
-                        .map(|(addr, _)| *addr)
-                        .collect();
-                    let current_clients: Vec<usize> = self
-                        .connected_clients
-                        .iter()
-                        .filter(|&(_, state)| *state == ConnectionState::Connected)
-                        .map(|(addr, _)| *addr)
-                        .collect();
-
-                    for disconnected in disconnected_clients {
-                        for client in &current_clients {
-                            self.bundle
-                                .reliable_sender
-                                .send(SignedMessage::<ReliableMessage> {
-                                    id: *client,
-                                    message: ReliableMessage::Disconnected(
-                                        disconnected.to_string(),
-                                    ),
-                                })
-                                .await
-                                .unwrap();
-                        }
-                    }
-                    for connected in &new_clients {
-                        self.bundle
-                            .reliable_sender
-                            .send(SignedMessage {
-                                id: *connected,
-                                message: ReliableMessage::Connect,
-                            })
-                            .await
-                            .unwrap();
-                        self.send_game_state(*connected).await;
-                        for client in &current_clients {
-                            self.bundle
-                                .reliable_sender
-                                .send(SignedMessage::<ReliableMessage> {
-                                    id: *client,
-                                    message: ReliableMessage::Connected(connected.to_string()),
-                                })
-                                .await
-                                .unwrap();
-                        }
-                    }
-                    self.connected_clients.clear();
-                    new_clients.into_iter().for_each(|x| {
-                        self.initialize_new_client(x);
-                        self.connected_clients.insert(x, ConnectionState::Connected);
-                    });
-                    current_clients.into_iter().for_each(|x| {
-                        self.connected_clients.insert(x, ConnectionState::Connected);
-                    });
-                }
-            }
+    async fn tick(&mut self) {
+        while let Ok(event) = self.bundle.events.try_recv() {
+            self.host.handle_event(event);
+        }
+        while let Ok(message) = self.bundle.receiver.try_recv() {
+            self.host.handle_message(message);
+        }
+        self.host.tick();
+        for message in self.host.outgoing() {
+            self.bundle.sender.send(message).await.unwrap();
         }
     }
 
-    pub fn initialize_new_client(&mut self, client_id: usize) {
-        let player = Player {
-            position: (0.0, 0.0)
-        };
-        self.players.insert(client_id as u32, player);
-    }
-
+    // Runs until the channels feeding the universe are closed.
     pub async fn run(&mut self) {
-        // I'm not going for efficiency here...
-
-        loop {
-            tokio::time::sleep(Duration::from_millis(16)).await;
-            if let Ok(message) = self.bundle.message_receiver.try_recv() {
-                match message.message {
-                    Message::Sync => {}
-                    Message::Position(x, y) => {
-                        for (id, _) in &self.connected_clients {
-                            self.bundle
-                                        .reliable_sender
-                                        .send(SignedMessage {
-                                            id: *id,
-                                            message: ReliableMessage::Text(format!("Client {} clicked {:?}", message.id, (x, y))),
-                                        })
-                                        .await
-                                        .unwrap();
-                        }
-                    }
-                    Message::State(_) => {}
-                    Message::Unknown => {}
-                    Message::Player(_, _) => {}
-                }
-                
+        self.timestep.delta();
+        while !self.bundle.receiver.is_closed() {
+            self.accumulator += self.timestep.delta();
+            if self.accumulator > self.ms_per_tick * MAX_CATCHUP_TICKS as FP {
+                let skipped = (self.accumulator / self.ms_per_tick) as u32 - MAX_CATCHUP_TICKS;
+                tracing::warn!("Server is behind, skipping {} ticks", skipped);
+                self.host.record_skipped_ticks(skipped);
+                self.accumulator = self.ms_per_tick * MAX_CATCHUP_TICKS as FP;
             }
-            self.client_tick().await;
-            continue;
-            for (player, pos) in &self.players {
-                for (id, _) in &self.connected_clients {
-                    self.bundle
-                        .message_sender
-                        .send(SignedMessage {
-                            id: *id,
-                            message: Message::Player(*player, pos.position)
-                        })
-                        .await
-                        .unwrap();
+            while self.accumulator >= self.ms_per_tick {
+                let started = Instant::now();
+                self.tick().await;
+                self.accumulator -= self.ms_per_tick;
+
+                let elapsed = started.elapsed().as_micros() as FP * 0.001;
+                self.host.record_tick_time(elapsed, self.ms_per_tick);
+                if elapsed > self.ms_per_tick {
+                    tracing::warn!(
+                        "Tick {} overran, took {:.2}ms of its {:.2}ms",
+                        self.host.simulation().tick(),
+                        elapsed,
+                        self.ms_per_tick
+                    );
                 }
             }
-
+            let remaining = self.ms_per_tick - self.accumulator;
+            tokio::time::sleep(Duration::from_micros((remaining * 1000.0) as u64)).await;
         }
     }
 }
@@ -1,23 +1,36 @@
 #![recursion_limit = "512"]
 mod game;
-mod runtime;
+mod room;
 mod cluster;
+mod transport;
 
 use std::time::Duration;
-use common::message::{RawMessage, SignedMessage, Target};
+use async_channel::Receiver;
+use common::message::{GameMessage, GameProtocol, RawMessage, SignedMessage, Target};
+use common::event::ConnectionEvent;
+use common::interest::Interest;
 use futures::{pin_mut, FutureExt as FExt};
 use futures_util::select;
-use game::{ChannelBundle, Universe};
+use room::{Rooms, SharedRooms, EMPTY_ROOM_TIMEOUT};
 use hyper::{
     server::conn::AddrStream,
     service::{make_service_fn, service_fn},
     Body, Error, Method, Response, Server, StatusCode,
 };
-use runtime::NativeRuntime;
-use std::{collections::{HashMap, HashSet}, net::SocketAddr, process};
+use common::runtime::NativeRuntime;
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::SocketAddr,
+    process,
+    sync::{Arc, Mutex},
+};
 use tokio_compat_02::FutureExt;
 use tracing_subscriber::fmt::format::FmtSpan;
-use webrtc_unreliable::{MessageType, Server as RtcServer};
+use transport::{
+    Both, Transport, TransportError, TransportEvent, UdpTransport, WebRtcTransport, WebSocketTransport,
+};
+use webrtc_unreliable::Server as RtcServer;
 use crate::cluster::{GameServer, get_game_server};
 
 #[derive(Clone)]
@@ -44,6 +57,14 @@ impl Router {
             handler_request.request.uri().path(),
         ) {
             (&Method::POST, "/session") => self.handler.post_session(handler_request).await,
+            (&Method::GET, "/session") => self.handler.upgrade_session(handler_request),
+            (&Method::GET, "/rooms") => self.handler.list_rooms(),
+            (&Method::POST, "/rooms") => self.handler.create_room(),
+            (&Method::POST, "/rooms/quick-match") => self.handler.quick_match(),
+            (&Method::GET, path) if path.starts_with("/rooms/") => {
+                let code = path["/rooms/".len()..].to_string();
+                self.handler.get_room(&code)
+            }
             (method, path) => {
                 tracing::info!("Unexpected request. Method: {}, Path: {}", method, path);
                 let mut response = Response::default();
@@ -54,16 +75,23 @@ impl Router {
     }
 }
 
-pub enum InternalMessage {
-    ClientSnapshot(HashSet<usize>),
+// Overridden with the TICK_RATE environment variable.
+const DEFAULT_TICK_RATE: u32 = 30;
+// Overridden with the MAX_ROOMS environment variable. Every room runs its own tick
+// loop, so this bounds how much simulating the server signs up for.
+const DEFAULT_MAX_ROOMS: usize = 64;
+// Overridden with the TRANSPORTS and UDP_PORT environment variables.
+const DEFAULT_TRANSPORTS: &str = "webrtc,websocket";
+const DEFAULT_UDP_PORT: u16 = 42425;
+const STATS_LOG_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+// Which multiplexer connection belongs to which transport address.
+pub struct ClientLookup<A> {
+    client_lookup: HashMap<A, usize>,
+    reverse_lookup: HashMap<usize, A>,
 }
 
-pub struct ClientLookup {
-    client_lookup: HashMap<SocketAddr, usize>,
-    reverse_lookup: HashMap<usize, SocketAddr>,
-}
-
-impl ClientLookup {
+impl<A: Copy + Eq + Hash> ClientLookup<A> {
     pub fn new() -> Self {
         Self {
             client_lookup: HashMap::new(),
@@ -71,32 +99,34 @@ impl ClientLookup {
         }
     }
 
-    pub fn register(&mut self, id: usize, socket: SocketAddr) {
-        self.client_lookup.insert(socket, id);
-        self.reverse_lookup.insert(id, socket);
+    pub fn register(&mut self, id: usize, address: A) {
+        self.client_lookup.insert(address, id);
+        self.reverse_lookup.insert(id, address);
     }
 
-    pub fn lookup(&self, id: usize) -> Option<&SocketAddr> {
+    pub fn lookup(&self, id: usize) -> Option<&A> {
         self.reverse_lookup.get(&id)
     }
 
-    pub fn lookup_socket(&self, socket: &SocketAddr) -> Option<&usize> {
-        self.client_lookup.get(socket)
+    pub fn lookup_address(&self, address: &A) -> Option<&usize> {
+        self.client_lookup.get(address)
     }
 
-    pub fn remove(&mut self, socket: &SocketAddr) {
-        if let Some(id) = self.client_lookup.get(socket) {
+    pub fn remove(&mut self, address: &A) {
+        if let Some(id) = self.client_lookup.get(address) {
             self.reverse_lookup.remove(id);
         }
-        self.client_lookup.remove(socket);
+        self.client_lookup.remove(address);
     }
 
-    pub fn contains(&self, socket: &SocketAddr) -> bool {
-        self.client_lookup.contains_key(socket)
+    pub fn remove_id(&mut self, id: usize) {
+        if let Some(address) = self.reverse_lookup.remove(&id) {
+            self.client_lookup.remove(&address);
+        }
     }
 
-    pub fn clients(&self) -> Vec<&SocketAddr> {
-        self.client_lookup.keys().collect()
+    pub fn contains(&self, address: &A) -> bool {
+        self.client_lookup.contains_key(address)
     }
 
     pub fn ids(&self) -> Vec<&usize> {
@@ -104,6 +134,27 @@ impl ClientLookup {
     }
 }
 
+impl<A: Copy + Eq + Hash> Default for ClientLookup<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Runs hyper's connections on our tokio, still able to reach the older tokio hyper
+// does its I/O on. Handlers can spawn their own tasks that way.
+#[derive(Clone)]
+struct CompatExecutor;
+
+impl<F> hyper::rt::Executor<F> for CompatExecutor
+where
+    F: std::future::Future + Send + 'static,
+    F::Output: Send,
+{
+    fn execute(&self, future: F) {
+        tokio::spawn(future.compat());
+    }
+}
+
 #[tokio::main]
 async fn main() {
     ctrlc::set_handler(move || {
@@ -122,12 +173,73 @@ async fn main() {
     let public_port = gameserver.address().parse().unwrap();
     let session_port: SocketAddr = "[::]:8081".parse().unwrap();
 
-    let mut rtc_server = RtcServer::new(data_port, public_port).await.unwrap();
+    // Comma separated list of "webrtc", "websocket" and "udp".
+    let transports = std::env::var("TRANSPORTS").unwrap_or_else(|_| DEFAULT_TRANSPORTS.to_string());
+    let transports: Vec<&str> = transports.split(',').map(str::trim).collect();
+    let webrtc = if transports.contains(&"webrtc") {
+        let rtc_server = RtcServer::new(data_port, public_port).await.unwrap();
+        tracing::info!("Accepting WebRTC clients on {}.", data_port);
+        Some(WebRtcTransport::new(rtc_server))
+    } else {
+        None
+    };
+    let udp = if transports.contains(&"udp") {
+        let udp_port = std::env::var("UDP_PORT")
+            .ok()
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(DEFAULT_UDP_PORT);
+        let udp_address = SocketAddr::from(([0, 0, 0, 0], udp_port));
+        let transport = UdpTransport::bind(udp_address).unwrap();
+        tracing::info!("Accepting UDP clients on {}.", udp_address);
+        Some(transport)
+    } else {
+        None
+    };
+
+    // Browsers fall back to WebSockets on the session port when WebRTC won't connect.
+    let websocket = if transports.contains(&"websocket") {
+        tracing::info!("Accepting WebSocket clients on {}.", session_port);
+        Some(WebSocketTransport::new())
+    } else {
+        None
+    };
 
-    let session_endpoint = rtc_server.session_endpoint();
+    let session_endpoint = webrtc.as_ref().map(WebRtcTransport::session_endpoint);
+    let websocket_acceptor = websocket.as_ref().map(WebSocketTransport::acceptor);
+
+    let (outgoing_sender, outgoing_receiver) = async_channel::unbounded();
+    let tick_rate = std::env::var("TICK_RATE")
+        .ok()
+        .and_then(|rate| rate.parse().ok())
+        .unwrap_or(DEFAULT_TICK_RATE);
+    tracing::info!("Simulating at {} ticks per second.", tick_rate);
+    let interest = std::env::var("INTEREST_RADIUS")
+        .ok()
+        .and_then(|radius| radius.parse().ok())
+        .map_or(Interest::default(), Interest::Radius);
+    tracing::info!("Clients see objects within {:?}.", interest);
+    let max_rooms = std::env::var("MAX_ROOMS")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(DEFAULT_MAX_ROOMS);
+    tracing::info!("Running at most {} rooms.", max_rooms);
+    let rooms: SharedRooms = Arc::new(Mutex::new(Rooms::new(outgoing_sender, tick_rate, interest, max_rooms)));
+
+    let sweep_rooms = rooms.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(EMPTY_ROOM_TIMEOUT).await;
+            sweep_rooms.lock().unwrap().close_empty();
+        }
+    });
 
+    let lobby_rooms = rooms.clone();
     let server = make_service_fn(move |addr_stream: &AddrStream| {
-        let handler = handlers::Handler::new(session_endpoint.clone());
+        let handler = handlers::Handler::new(
+            session_endpoint.clone(),
+            websocket_acceptor.clone(),
+            lobby_rooms.clone(),
+        );
         let router = Router::new(handler);
         let remote_addr = addr_stream.remote_addr();
         async move {
@@ -140,38 +252,53 @@ async fn main() {
 
     tokio::spawn(
         async move {
-            Server::bind(&session_port).serve(server).await.unwrap();
+            Server::bind(&session_port)
+                .executor(CompatExecutor)
+                .serve(server)
+                .await
+                .unwrap();
         }
         .compat(),
     );
-    let (signed_packet_sender, signed_packet_receiver) = async_channel::unbounded();
-    let mut multiplexer =
-        common::multiplexer::ConnectionMultiplexer::new(NativeRuntime::new(), signed_packet_sender);
-
-    let reliable_channel = common::processor::BidirectionalChannel::new();
-    let message_channel = common::processor::BidirectionalChannel::new();
-    let (internal_sender, internal_receiver) = async_channel::unbounded();
-    let bundle = ChannelBundle {
-        reliable_sender: reliable_channel.outgoing_sender,
-        reliable_receiver: reliable_channel.incoming_reciever,
-        message_sender: message_channel.outgoing_sender,
-        message_receiver: message_channel.incoming_reciever,
-        internal_receiver,
-    };
-    let mut universe = Universe::new(bundle);
+    tracing::info!("Game server started.");
+    if webrtc.is_none() && websocket.is_none() && udp.is_none() {
+        tracing::error!("No transports enabled, set TRANSPORTS to any of webrtc, websocket and udp.");
+        return;
+    }
+    serve(Both::new(Both::new(webrtc, websocket), udp), rooms, outgoing_receiver).await;
+}
 
-    tokio::spawn(async move {
-        universe.run().await;
-    });
+// Runs every connection that comes in over `transport`, hands their messages to the
+// rooms and sends back what the rooms put on `outgoing`.
+async fn serve<T: Transport>(
+    mut transport: T,
+    rooms: SharedRooms,
+    outgoing: Receiver<SignedMessage<GameMessage>>,
+) {
+    let (signed_packet_sender, signed_packet_receiver) = async_channel::unbounded();
+    let mut multiplexer = common::multiplexer::ConnectionMultiplexer::<_, GameProtocol>::new(
+        NativeRuntime::new(),
+        signed_packet_sender,
+    );
 
     let incoming_message = multiplexer.message_receiver();
-    let incoming_reliable_message = multiplexer.reliable_message_receiver();
+    let connection_events = multiplexer.subscribe();
 
     let mut client_lookup = ClientLookup::new();
-    tracing::info!("Game server started.");
+    let mut last_stats_log = std::time::Instant::now();
     loop {
+        if last_stats_log.elapsed() >= STATS_LOG_INTERVAL {
+            last_stats_log = std::time::Instant::now();
+            tracing::info!("Rooms: {:?}", rooms.lock().unwrap().list());
+            for id in client_lookup.ids() {
+                if let Ok(stats) = multiplexer.stats(*id) {
+                    tracing::info!("Connection {} stats: {:?}", id, stats);
+                }
+            }
+        }
+
         let pending_packet = {
-            let recieve = rtc_server.recv().fuse();
+            let recieve = transport.recv().fuse();
             pin_mut!(recieve);
 
             let pending_send = signed_packet_receiver.recv().fuse();
@@ -180,26 +307,42 @@ async fn main() {
             let incoming_message = incoming_message.recv().fuse();
             pin_mut!(incoming_message);
 
-            let incoming_reliable_message = incoming_reliable_message.recv().fuse();
-            pin_mut!(incoming_reliable_message);
-
-            let outgoing_reliable_message = reliable_channel.outgoing_receiver.recv().fuse();
-            pin_mut!(outgoing_reliable_message);
-
-            let outgoing_message = message_channel.outgoing_receiver.recv().fuse();
+            let outgoing_message = outgoing.recv().fuse();
             pin_mut!(outgoing_message);
+
+            let connection_event = connection_events.recv().fuse();
+            pin_mut!(connection_event);
             let pending_packet = select! {
                 received = recieve => {
-
-                    if let Ok(received) = received {
-                        if !client_lookup.contains(&received.remote_addr) {
-                            tracing::info!("Received new client {:?}", &received.remote_addr);
-                            let id = multiplexer.register();
-                            client_lookup.register(id, received.remote_addr);
-                            internal_sender.send(InternalMessage::ClientSnapshot(client_lookup.ids().into_iter().map(|x| *x).collect())).await;
+                    match received {
+                        Ok(TransportEvent::Connected(address)) => {
+                            if !client_lookup.contains(&address) {
+                                tracing::info!("Received new client {:?}", address);
+                                let id = multiplexer.register();
+                                client_lookup.register(id, address);
+                            }
                         }
-                        tracing::info!("Received message from client {:?}", &received.remote_addr);
-                        multiplexer.send_raw(Target::Client(*client_lookup.lookup_socket(&received.remote_addr).unwrap()), received.message.as_ref().into()).await;
+                        Ok(TransportEvent::Received(address, message)) => {
+                            if !client_lookup.contains(&address) {
+                                tracing::info!("Received new client {:?}", address);
+                                let id = multiplexer.register();
+                                client_lookup.register(id, address);
+                            }
+                            tracing::info!("Received message from client {:?}", address);
+                            let id = *client_lookup.lookup_address(&address).unwrap();
+                            if let Err(err) = multiplexer.send_raw(Target::Client(id), message) {
+                                tracing::warn!("Dropping packet from {:?}: {}", address, err);
+                            }
+                        }
+                        Ok(TransportEvent::Disconnected(address)) => {
+                            if let Some(id) = client_lookup.lookup_address(&address) {
+                                if let Err(err) = multiplexer.kill(*id) {
+                                    tracing::warn!("Failed to kill connection: {}", err);
+                                }
+                            }
+                            client_lookup.remove(&address);
+                        }
+                        Err(err) => tracing::warn!("Failed to receive: {}", err),
                     }
                     Action::None
                 },
@@ -212,25 +355,33 @@ async fn main() {
                 },
                 message = incoming_message => {
                     if let Ok(message) = message {
-                        message_channel.incoming_sender.send(message).await.unwrap();
+                        rooms.lock().unwrap().route(message);
                     }
                     Action::None
                 },
-                message = incoming_reliable_message => {
+                message = outgoing_message => {
                     if let Ok(message) = message {
-                        reliable_channel.incoming_sender.send(message).await.unwrap();
+                        if let Err(err) = multiplexer.send_message(Target::Client(message.id), message.message) {
+                            tracing::warn!("Failed to send message: {}", err);
+                        }
                     }
                     Action::None
                 },
-                message = outgoing_reliable_message => {
-                    if let Ok(message) = message {
-                        multiplexer.send_reliable_message(Target::Client(message.id), message.message).await;
+                event = connection_event => {
+                    if let Ok(event) = &event {
+                        rooms.lock().unwrap().handle_event(event.clone());
                     }
-                    Action::None
-                }
-                message = outgoing_message => {
-                    if let Ok(message) = message {
-                        multiplexer.send_message(Target::Client(message.id), message.message).await;
+                    match event {
+                        Ok(ConnectionEvent::Disconnected(id, reason)) => {
+                            tracing::info!("Dropping client {}: {:?}", id, reason);
+                            let _ = multiplexer.kill(id);
+                            client_lookup.remove_id(id);
+                        }
+                        Ok(ConnectionEvent::ProcessorCrashed(id, _)) => {
+                            let _ = multiplexer.kill(id);
+                            client_lookup.remove_id(id);
+                        }
+                        _ => {}
                     }
                     Action::None
                 }
@@ -239,36 +390,13 @@ async fn main() {
         };
 
         if let Action::Send(packet) = pending_packet {
-            let clients: Vec<SocketAddr> =
-                rtc_server.connected_clients().map(|x| x.clone()).collect();
-
-
-            if let Some(client) = client_lookup.lookup(packet.id) {
+            if let Some(client) = client_lookup.lookup(packet.id).copied() {
                 tracing::info!("Sending message {:?}", client);
-                if rtc_server.is_connected(client) {
-                    rtc_server
-                        .send(packet.message.as_ref(), MessageType::Binary, client)
-                        .await
-                        .unwrap();
+                match transport.send(client, packet.message.as_ref()).await {
+                    Ok(()) | Err(TransportError::NotConnected) => {}
+                    Err(err) => tracing::warn!("Failed to send to {:?}: {}", client, err),
                 }
             }
-
-            let mut disconnected = vec![];
-
-            client_lookup
-                .clients()
-                .iter()
-                .filter(|x| !clients.contains(*x))
-                .for_each(|client| {
-                    if let Some(target) = client_lookup.lookup_socket(&client) {
-                        multiplexer.kill(*target);
-                        disconnected.push(*client.clone());
-                    }
-                });
-
-            disconnected.into_iter().for_each(|client| {
-                client_lookup.remove(&client);
-            });
         };
     }
 }
@@ -276,7 +404,6 @@ async fn main() {
 pub enum Action {
     None,
     Send(SignedMessage<RawMessage>),
-    Incoming(),
 }
 
 mod handlers {
@@ -286,11 +413,19 @@ mod handlers {
         header::{self, HeaderValue},
         Body, Response, StatusCode,
     };
+    use tokio_compat_02::FutureExt;
     use webrtc_unreliable::SessionEndpoint;
 
+    use crate::room::{JoinError, SharedRooms};
+    use crate::transport::WebSocketAcceptor;
+
     #[derive(Clone)]
     pub struct Handler {
-        session_endpoint: SessionEndpoint,
+        // `None` when the server doesn't accept WebRTC clients.
+        session_endpoint: Option<SessionEndpoint>,
+        // `None` when the server doesn't accept WebSocket clients.
+        websocket: Option<WebSocketAcceptor>,
+        rooms: SharedRooms,
     }
 
     pub struct Request {
@@ -299,8 +434,16 @@ mod handlers {
     }
 
     impl Handler {
-        pub fn new(session_endpoint: SessionEndpoint) -> Self {
-            Self { session_endpoint }
+        pub fn new(
+            session_endpoint: Option<SessionEndpoint>,
+            websocket: Option<WebSocketAcceptor>,
+            rooms: SharedRooms,
+        ) -> Self {
+            Self {
+                session_endpoint,
+                websocket,
+                rooms,
+            }
         }
 
         fn response<S: Into<String>>(
@@ -312,6 +455,113 @@ mod handlers {
                 .body(Body::from(message.into()))
         }
 
+        fn json<T: serde::Serialize>(
+            status: StatusCode,
+            value: &T,
+        ) -> Result<Response<Body>, hyper::http::Error> {
+            let body = serde_json::to_string(value).expect("lobby responses always serialize");
+            Response::builder()
+                .status(status)
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+                .body(Body::from(body))
+        }
+
+        fn join_error(err: JoinError) -> Result<Response<Body>, hyper::http::Error> {
+            let status = match err {
+                JoinError::UnknownRoom => StatusCode::NOT_FOUND,
+                JoinError::Full => StatusCode::CONFLICT,
+                JoinError::TooManyRooms => StatusCode::SERVICE_UNAVAILABLE,
+                JoinError::TooManyEmptyRooms => StatusCode::TOO_MANY_REQUESTS,
+            };
+            Response::builder()
+                .status(status)
+                .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+                .body(Body::from(err.to_string()))
+        }
+
+        pub fn list_rooms(&self) -> Result<Response<Body>, hyper::http::Error> {
+            Self::json(StatusCode::OK, &self.rooms.lock().unwrap().list())
+        }
+
+        pub fn create_room(&self) -> Result<Response<Body>, hyper::http::Error> {
+            let mut rooms = self.rooms.lock().unwrap();
+            let info = rooms.create().and_then(|code| rooms.info(&code));
+            match info {
+                Ok(info) => Self::json(StatusCode::CREATED, &info),
+                Err(err) => Self::join_error(err),
+            }
+        }
+
+        // Picks the fullest room with space left, making one if they're all full.
+        pub fn quick_match(&self) -> Result<Response<Body>, hyper::http::Error> {
+            let mut rooms = self.rooms.lock().unwrap();
+            let info = rooms.quick_match().and_then(|code| rooms.info(&code));
+            match info {
+                Ok(info) => Self::json(StatusCode::OK, &info),
+                Err(err) => Self::join_error(err),
+            }
+        }
+
+        pub fn get_room(&self, code: &str) -> Result<Response<Body>, hyper::http::Error> {
+            match self.rooms.lock().unwrap().info(code) {
+                Ok(info) => Self::json(StatusCode::OK, &info),
+                Err(err) => Self::join_error(err),
+            }
+        }
+
+        // Clients say which room they're after with `?room=CODE` and join it once
+        // connected, turn them away now if that can't work.
+        fn check_requested_room(&self, request: &Request) -> Result<(), JoinError> {
+            let room = request.request.uri().query().and_then(|query| {
+                query
+                    .split('&')
+                    .find(|pair| pair.starts_with("room="))
+                    .map(|pair| pair["room=".len()..].to_string())
+            });
+            match room {
+                Some(room) => self.rooms.lock().unwrap().check(&room),
+                None => Ok(()),
+            }
+        }
+
+        // The WebSocket fallback for clients that can't get a WebRTC session going.
+        pub fn upgrade_session(&self, request: Request) -> Result<Response<Body>, hyper::http::Error> {
+            let acceptor = match self.websocket.clone() {
+                Some(acceptor) => acceptor,
+                None => return Self::response(StatusCode::NOT_FOUND, "WebSockets are disabled"),
+            };
+            let is_websocket = request
+                .request
+                .headers()
+                .get(header::UPGRADE)
+                .is_some_and(|upgrade| upgrade.as_bytes().eq_ignore_ascii_case(b"websocket"));
+            let key = match request.request.headers().get(header::SEC_WEBSOCKET_KEY) {
+                Some(key) if is_websocket => WebSocketAcceptor::accept_key(key.as_bytes()),
+                _ => return Self::response(StatusCode::BAD_REQUEST, "Expected a WebSocket upgrade"),
+            };
+            if let Err(err) = self.check_requested_room(&request) {
+                return Self::join_error(err);
+            }
+            tracing::info!("Upgrading {} to a WebSocket", request.remote_address);
+            let address = request.remote_address;
+            tokio::spawn(
+                async move {
+                    match request.request.into_body().on_upgrade().await {
+                        Ok(upgraded) => acceptor.serve(address, upgraded).await,
+                        Err(err) => tracing::warn!("WebSocket upgrade for {} failed: {}", address, err),
+                    }
+                }
+                .compat(),
+            );
+            Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(header::UPGRADE, "websocket")
+                .header(header::CONNECTION, "Upgrade")
+                .header(header::SEC_WEBSOCKET_ACCEPT, key)
+                .body(Body::empty())
+        }
+
         pub async fn post_session(
             mut self,
             request: Request,
@@ -320,8 +570,15 @@ mod handlers {
                 "Received RTC session request from {}",
                 request.remote_address
             );
-            match self
-                .session_endpoint
+            if let Err(err) = self.check_requested_room(&request) {
+                return Self::join_error(err);
+            }
+            let session_endpoint = match self.session_endpoint.as_mut() {
+                Some(session_endpoint) => session_endpoint,
+                // Not a 404, browsers take that for a room that doesn't exist.
+                None => return Self::response(StatusCode::NOT_IMPLEMENTED, "WebRTC is disabled"),
+            };
+            match session_endpoint
                 .http_session_request(request.request.into_body())
                 .await
             {
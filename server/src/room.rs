@@ -0,0 +1,522 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_channel::Sender;
+use common::event::{ConnectionEvent, DisconnectReason};
+use common::interest::Interest;
+use common::message::{GameMessage, ReliableMessage, SignedMessage};
+use rand::Rng;
+
+use crate::game::{ChannelBundle, Universe};
+
+// Clients that don't pick a room are put in the fullest one with space left.
+pub const ROOM_CAPACITY: usize = 8;
+const CODE_LENGTH: usize = 4;
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ";
+// Rooms stay open this long without anyone in them, so a room made in the lobby
+// survives until its creator connects.
+pub const EMPTY_ROOM_TIMEOUT: Duration = Duration::from_secs(30);
+// Anyone can create a room without joining it, this many waiting for players is enough.
+const MAX_EMPTY_ROOMS: usize = 16;
+
+// The lobby and the packet loop both need the rooms.
+pub type SharedRooms = Arc<Mutex<Rooms>>;
+
+#[derive(serde::Serialize, Debug)]
+pub struct RoomInfo {
+    pub code: String,
+    pub players: usize,
+    pub capacity: usize,
+}
+
+// Why a client can't get into a room.
+#[derive(Debug, PartialEq)]
+pub enum JoinError {
+    UnknownRoom,
+    Full,
+    // There would be more rooms than the server is willing to run.
+    TooManyRooms,
+    // Enough rooms are already waiting for players to show up.
+    TooManyEmptyRooms,
+}
+
+impl std::fmt::Display for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinError::UnknownRoom => write!(f, "No room with that code"),
+            JoinError::Full => write!(f, "The room is full"),
+            JoinError::TooManyRooms => write!(f, "The server can't open any more rooms"),
+            JoinError::TooManyEmptyRooms => write!(f, "Too many rooms are waiting for players"),
+        }
+    }
+}
+
+struct Room {
+    epoch: u32,
+    incoming: Sender<SignedMessage<GameMessage>>,
+    events: Sender<ConnectionEvent>,
+    members: HashSet<usize>,
+    empty_since: Option<Instant>,
+}
+
+impl Room {
+    fn close(&self) {
+        self.incoming.close();
+        self.events.close();
+    }
+}
+
+// Runs a `Universe` per room and routes every client's messages and connection events
+// to the room it is in. Clients pick a room, or ask to be matched into one, with
+// `ReliableMessage::JoinRoom` and nothing they send reaches a room before that. Rooms
+// are created when someone needs one and closed once they've been empty for a while.
+pub struct Rooms {
+    rooms: HashMap<String, Room>,
+    membership: HashMap<usize, String>,
+    // Clients the multiplexer has registered and not dropped yet. Messages still in
+    // flight from one that's gone mustn't put it back in a room.
+    registered: HashSet<usize>,
+    // Clients whose connection is up, as opposed to just registered.
+    connected: HashSet<usize>,
+    outgoing: Sender<SignedMessage<GameMessage>>,
+    tick_rate: u32,
+    interest: Interest,
+    max_rooms: usize,
+    // Handed to the next room, so no two rooms ever share one.
+    next_epoch: u32,
+}
+
+impl Rooms {
+    pub fn new(
+        outgoing: Sender<SignedMessage<GameMessage>>,
+        tick_rate: u32,
+        interest: Interest,
+        max_rooms: usize,
+    ) -> Self {
+        Self {
+            rooms: HashMap::new(),
+            membership: HashMap::new(),
+            registered: HashSet::new(),
+            connected: HashSet::new(),
+            outgoing,
+            tick_rate,
+            interest,
+            max_rooms,
+            next_epoch: 0,
+        }
+    }
+
+    pub fn list(&self) -> Vec<RoomInfo> {
+        self.rooms
+            .keys()
+            .filter_map(|code| self.info(code).ok())
+            .collect()
+    }
+
+    pub fn info(&self, code: &str) -> Result<RoomInfo, JoinError> {
+        let room = self.rooms.get(code).ok_or(JoinError::UnknownRoom)?;
+        Ok(RoomInfo {
+            code: code.to_string(),
+            players: room.members.len(),
+            capacity: ROOM_CAPACITY,
+        })
+    }
+
+    // Whether a client could join the room right now.
+    pub fn check(&self, code: &str) -> Result<(), JoinError> {
+        if self.info(code)?.players >= ROOM_CAPACITY {
+            return Err(JoinError::Full);
+        }
+        Ok(())
+    }
+
+    // Starts an empty room and returns its code, unless there are as many rooms as the
+    // server is allowed to run or enough of them are empty already.
+    pub fn create(&mut self) -> Result<String, JoinError> {
+        if self.rooms.len() >= self.max_rooms {
+            return Err(JoinError::TooManyRooms);
+        }
+        let empty = self
+            .rooms
+            .values()
+            .filter(|room| room.members.is_empty())
+            .count();
+        if empty >= MAX_EMPTY_ROOMS {
+            return Err(JoinError::TooManyEmptyRooms);
+        }
+        let code = loop {
+            let code = random_code();
+            if !self.rooms.contains_key(&code) {
+                break code;
+            }
+        };
+        let (incoming, receiver) = async_channel::unbounded();
+        let (events, event_receiver) = async_channel::unbounded();
+        let bundle = ChannelBundle {
+            sender: self.outgoing.clone(),
+            receiver,
+            events: event_receiver,
+        };
+        let epoch = self.next_epoch;
+        self.next_epoch = self.next_epoch.wrapping_add(1);
+        let mut universe = Universe::new(bundle, self.tick_rate, self.interest, epoch);
+        tokio::spawn(async move {
+            universe.run().await;
+        });
+        tracing::info!("Created room {}", code);
+        self.rooms.insert(
+            code.clone(),
+            Room {
+                epoch,
+                incoming,
+                events,
+                members: HashSet::new(),
+                empty_since: Some(Instant::now()),
+            },
+        );
+        Ok(code)
+    }
+
+    pub fn close_empty(&mut self) {
+        let expired: Vec<String> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| {
+                matches!(room.empty_since, Some(since) if since.elapsed() >= EMPTY_ROOM_TIMEOUT)
+            })
+            .map(|(code, _)| code.clone())
+            .collect();
+        for code in expired {
+            tracing::info!("Closing empty room {}", code);
+            if let Some(room) = self.rooms.remove(&code) {
+                room.close();
+            }
+        }
+    }
+
+    // The fullest room that still has space, or a new one if there may be more.
+    pub fn quick_match(&mut self) -> Result<String, JoinError> {
+        let fullest = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.members.len() < ROOM_CAPACITY)
+            .max_by_key(|(_, room)| room.members.len())
+            .map(|(code, _)| code.clone());
+        match fullest {
+            Some(code) => Ok(code),
+            None => self.create(),
+        }
+    }
+
+    pub fn handle_event(&mut self, event: ConnectionEvent) {
+        match event {
+            // Rooms only hear about a client once it joins one.
+            ConnectionEvent::Registered(id) => {
+                self.registered.insert(id);
+            }
+            ConnectionEvent::Connected(id) => {
+                self.connected.insert(id);
+                self.forward_event(id, event);
+            }
+            ConnectionEvent::Disconnected(id, _) | ConnectionEvent::ProcessorCrashed(id, _) => {
+                self.registered.remove(&id);
+                self.connected.remove(&id);
+                self.forward_event(id, event);
+                self.leave(id);
+            }
+        }
+    }
+
+    // Hands a client's message to its room. Requests to switch rooms are handled here
+    // and never reach a room.
+    pub fn route(&mut self, message: SignedMessage<GameMessage>) {
+        if let GameMessage::Reliable(ReliableMessage::JoinRoom(code)) = &message.message {
+            self.join(message.id, code.clone());
+            return;
+        }
+        let room = self
+            .membership
+            .get(&message.id)
+            .and_then(|code| self.rooms.get(code));
+        if let Some(room) = room {
+            if room.incoming.try_send(message).is_err() {
+                tracing::warn!("Room closed, dropping message");
+            }
+        }
+    }
+
+    // Moves a client to the room with `code`, or the best match without one. Clients
+    // that can't get into the room they asked for stay where they are, or are matched
+    // if they aren't anywhere yet. They are told when there's no room for them at all.
+    pub fn join(&mut self, id: usize, code: Option<String>) {
+        if !self.registered.contains(&id) {
+            return;
+        }
+        let current = self.membership.get(&id).cloned();
+        let code = match code {
+            Some(code) if current.as_ref() == Some(&code) => return,
+            Some(code) => match self.check(&code) {
+                Ok(()) => Ok(code),
+                Err(err) => {
+                    tracing::info!("Client {} can't join room {}: {}", id, code, err);
+                    self.send(
+                        id,
+                        ReliableMessage::Text(format!("Can't join {}: {}", code, err)),
+                    );
+                    if current.is_some() {
+                        return;
+                    }
+                    self.quick_match()
+                }
+            },
+            None if current.is_some() => return,
+            None => self.quick_match(),
+        };
+        let code = match code {
+            Ok(code) => code,
+            Err(err) => {
+                tracing::info!("Client {} can't be matched into a room: {}", id, err);
+                self.send(
+                    id,
+                    ReliableMessage::Text(format!("Can't join a room: {}", err)),
+                );
+                return;
+            }
+        };
+        if current.is_some() {
+            self.forward_event(
+                id,
+                ConnectionEvent::Disconnected(id, DisconnectReason::Killed),
+            );
+            self.leave(id);
+        }
+        self.enter(id, code);
+    }
+
+    fn enter(&mut self, id: usize, code: String) {
+        tracing::info!("Client {} joined room {}", id, code);
+        let room = match self.rooms.get_mut(&code) {
+            Some(room) => room,
+            None => return,
+        };
+        room.members.insert(id);
+        room.empty_since = None;
+        let epoch = room.epoch;
+        self.membership.insert(id, code.clone());
+        self.forward_event(id, ConnectionEvent::Registered(id));
+        if self.connected.contains(&id) {
+            self.forward_event(id, ConnectionEvent::Connected(id));
+        }
+        self.send(id, ReliableMessage::JoinedRoom(code, epoch));
+    }
+
+    fn leave(&mut self, id: usize) {
+        let room = self
+            .membership
+            .remove(&id)
+            .and_then(|code| self.rooms.get_mut(&code));
+        if let Some(room) = room {
+            room.members.remove(&id);
+            if room.members.is_empty() {
+                room.empty_since = Some(Instant::now());
+            }
+        }
+    }
+
+    fn send(&self, id: usize, message: ReliableMessage) {
+        let message = SignedMessage {
+            id,
+            message: message.into(),
+        };
+        if self.outgoing.try_send(message).is_err() {
+            tracing::warn!("Failed to send room message to client {}", id);
+        }
+    }
+
+    fn forward_event(&self, id: usize, event: ConnectionEvent) {
+        let room = self
+            .membership
+            .get(&id)
+            .and_then(|code| self.rooms.get(code));
+        if let Some(room) = room {
+            if room.events.try_send(event).is_err() {
+                tracing::warn!("Room closed, dropping event");
+            }
+        }
+    }
+}
+
+fn random_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..CODE_LENGTH)
+        .map(|_| CODE_ALPHABET[rng.gen_range(0, CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use async_channel::Receiver;
+
+    use super::*;
+
+    const TICK_RATE: u32 = 20;
+
+    fn rooms(max_rooms: usize) -> (Rooms, Receiver<SignedMessage<GameMessage>>) {
+        let (outgoing, receiver) = async_channel::unbounded();
+        let rooms = Rooms::new(outgoing, TICK_RATE, Interest::default(), max_rooms);
+        (rooms, receiver)
+    }
+
+    fn join(rooms: &mut Rooms, id: usize, code: Option<&str>) {
+        rooms.route(SignedMessage {
+            id,
+            message: ReliableMessage::JoinRoom(code.map(str::to_string)).into(),
+        });
+    }
+
+    fn register_and_join(rooms: &mut Rooms, id: usize, code: Option<&str>) {
+        rooms.handle_event(ConnectionEvent::Registered(id));
+        join(rooms, id, code);
+    }
+
+    // What the rooms told `id` since the last call.
+    fn sent_to(receiver: &Receiver<SignedMessage<GameMessage>>, id: usize) -> Vec<ReliableMessage> {
+        let mut sent = Vec::new();
+        while let Ok(message) = receiver.try_recv() {
+            if let (true, GameMessage::Reliable(message)) = (message.id == id, message.message) {
+                sent.push(message);
+            }
+        }
+        sent
+    }
+
+    fn room_of(rooms: &Rooms, id: usize) -> Option<&str> {
+        rooms.membership.get(&id).map(String::as_str)
+    }
+
+    #[tokio::test]
+    async fn a_full_room_falls_back_to_a_match() {
+        let (mut rooms, receiver) = rooms(4);
+        let full = rooms.create().unwrap();
+        for id in 0..ROOM_CAPACITY {
+            register_and_join(&mut rooms, id, Some(&full));
+        }
+        assert_eq!(rooms.check(&full), Err(JoinError::Full));
+
+        register_and_join(&mut rooms, 100, Some(&full));
+        let room = room_of(&rooms, 100).unwrap().to_string();
+        assert_ne!(room, full);
+        let sent = sent_to(&receiver, 100);
+        assert!(matches!(&sent[..], [
+            ReliableMessage::Text(_),
+            ReliableMessage::JoinedRoom(code, _),
+        ] if *code == room));
+    }
+
+    #[tokio::test]
+    async fn a_refused_room_keeps_the_client_where_it_is() {
+        let (mut rooms, receiver) = rooms(4);
+        let first = rooms.create().unwrap();
+        register_and_join(&mut rooms, 1, Some(&first));
+        sent_to(&receiver, 1);
+
+        // Codes never have digits in them.
+        join(&mut rooms, 1, Some("0000"));
+        assert_eq!(room_of(&rooms, 1), Some(first.as_str()));
+        assert!(matches!(
+            &sent_to(&receiver, 1)[..],
+            [ReliableMessage::Text(_)]
+        ));
+
+        let full = rooms.create().unwrap();
+        for id in 10..10 + ROOM_CAPACITY {
+            register_and_join(&mut rooms, id, Some(&full));
+        }
+        join(&mut rooms, 1, Some(&full));
+        assert_eq!(room_of(&rooms, 1), Some(first.as_str()));
+        assert_eq!(rooms.info(&first).unwrap().players, 1);
+    }
+
+    #[tokio::test]
+    async fn rooms_stop_at_the_limit() {
+        let (mut rooms, receiver) = rooms(2);
+        for id in 0..2 * ROOM_CAPACITY {
+            register_and_join(&mut rooms, id, None);
+        }
+        assert_eq!(rooms.rooms.len(), 2);
+        assert_eq!(rooms.create(), Err(JoinError::TooManyRooms));
+
+        register_and_join(&mut rooms, 100, None);
+        assert_eq!(room_of(&rooms, 100), None);
+        assert!(matches!(
+            &sent_to(&receiver, 100)[..],
+            [ReliableMessage::Text(_)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn empty_rooms_stop_at_the_limit() {
+        let (mut rooms, _receiver) = rooms(MAX_EMPTY_ROOMS + 4);
+        let codes: Vec<String> = (0..MAX_EMPTY_ROOMS)
+            .map(|_| rooms.create().unwrap())
+            .collect();
+        assert_eq!(rooms.create(), Err(JoinError::TooManyEmptyRooms));
+
+        // A room somebody is in no longer counts.
+        register_and_join(&mut rooms, 1, Some(&codes[0]));
+        assert!(rooms.create().is_ok());
+    }
+
+    #[tokio::test]
+    async fn rooms_close_once_empty_for_long_enough() {
+        let (mut rooms, _receiver) = rooms(4);
+        let occupied = rooms.create().unwrap();
+        let empty = rooms.create().unwrap();
+        register_and_join(&mut rooms, 1, Some(&occupied));
+        for room in rooms.rooms.values_mut() {
+            if room.empty_since.is_some() {
+                room.empty_since = Some(Instant::now() - EMPTY_ROOM_TIMEOUT);
+            }
+        }
+        rooms.close_empty();
+        assert!(rooms.info(&occupied).is_ok());
+        assert_eq!(rooms.info(&empty).err(), Some(JoinError::UnknownRoom));
+    }
+
+    #[tokio::test]
+    async fn unregistered_clients_are_ignored() {
+        let (mut rooms, receiver) = rooms(4);
+        let code = rooms.create().unwrap();
+        join(&mut rooms, 1, Some(&code));
+        join(&mut rooms, 1, None);
+        assert_eq!(room_of(&rooms, 1), None);
+        assert!(sent_to(&receiver, 1).is_empty());
+
+        // Nor do messages in flight from a client that's gone put it back.
+        register_and_join(&mut rooms, 2, Some(&code));
+        rooms.handle_event(ConnectionEvent::Disconnected(2, DisconnectReason::Killed));
+        join(&mut rooms, 2, Some(&code));
+        assert_eq!(room_of(&rooms, 2), None);
+        assert_eq!(rooms.info(&code).unwrap().players, 0);
+    }
+
+    #[tokio::test]
+    async fn every_room_gets_its_own_epoch() {
+        let (mut rooms, receiver) = rooms(8);
+        rooms.handle_event(ConnectionEvent::Registered(1));
+        let mut epochs = HashSet::new();
+        for _ in 0..8 {
+            let code = rooms.create().unwrap();
+            join(&mut rooms, 1, Some(&code));
+            for message in sent_to(&receiver, 1) {
+                if let ReliableMessage::JoinedRoom(_, epoch) = message {
+                    assert!(epochs.insert(epoch));
+                }
+            }
+        }
+        assert_eq!(epochs.len(), 8);
+    }
+}
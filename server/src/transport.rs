@@ -0,0 +1,160 @@
+use std::{fmt, hash::Hash};
+
+use common::message::RawMessage;
+use futures::{
+    future::{self, BoxFuture},
+    FutureExt,
+};
+
+mod udp;
+mod webrtc;
+mod websocket;
+
+pub use udp::UdpTransport;
+pub use webrtc::WebRtcTransport;
+pub use websocket::{WebSocketAcceptor, WebSocketTransport};
+
+// Something that happened on a transport, for the peer at the given address.
+#[derive(Debug)]
+pub enum TransportEvent<A> {
+    Connected(A),
+    Received(A, RawMessage),
+    Disconnected(A),
+}
+
+impl<A> TransportEvent<A> {
+    pub fn map<B, F: FnOnce(A) -> B>(self, f: F) -> TransportEvent<B> {
+        match self {
+            TransportEvent::Connected(address) => TransportEvent::Connected(f(address)),
+            TransportEvent::Received(address, message) => {
+                TransportEvent::Received(f(address), message)
+            }
+            TransportEvent::Disconnected(address) => TransportEvent::Disconnected(f(address)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    NotConnected,
+    Failed(String),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::NotConnected => write!(f, "Peer is not connected"),
+            TransportError::Failed(err) => write!(f, "Transport failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+// Moves datagrams between the server and its peers. Connection state, reliability and
+// framing are all handled by the multiplexer on top, a transport only has to say who
+// showed up, what they sent, and when they're gone.
+pub trait Transport {
+    type Address: Copy + Eq + Hash + fmt::Debug + Send;
+
+    // Waits for the next event. A peer's first datagram may come without a `Connected`
+    // before it, the server treats either as the peer connecting.
+    fn recv(&mut self) -> BoxFuture<'_, Result<TransportEvent<Self::Address>, TransportError>>;
+
+    fn send<'a>(
+        &'a mut self,
+        to: Self::Address,
+        message: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), TransportError>>;
+}
+
+// A transport that may be turned off. One that is never sees anyone, so it can sit in
+// a `Both` like any other.
+impl<T: Transport> Transport for Option<T> {
+    type Address = T::Address;
+
+    fn recv(&mut self) -> BoxFuture<'_, Result<TransportEvent<Self::Address>, TransportError>> {
+        match self {
+            Some(transport) => transport.recv(),
+            None => future::pending().boxed(),
+        }
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        to: Self::Address,
+        message: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), TransportError>> {
+        match self {
+            Some(transport) => transport.send(to, message),
+            None => future::ready(Err(TransportError::NotConnected)).boxed(),
+        }
+    }
+}
+
+// Address of a peer on one of the two transports in a `Both`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BothAddress<A, B> {
+    First(A),
+    Second(B),
+}
+
+// Serves peers from two transports at once, like browsers over WebRTC alongside
+// native clients over UDP. Nest them for more.
+pub struct Both<A, B> {
+    first: A,
+    second: B,
+    // Which transport gets polled first on the next `recv`. Whichever goes first wins
+    // when both have something, so they take turns or a busy one starves the other.
+    second_first: bool,
+}
+
+impl<A, B> Both<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self {
+            first,
+            second,
+            second_first: false,
+        }
+    }
+}
+
+impl<A, B> Transport for Both<A, B>
+where
+    A: Transport + Send,
+    B: Transport + Send,
+{
+    type Address = BothAddress<A::Address, B::Address>;
+
+    fn recv(&mut self) -> BoxFuture<'_, Result<TransportEvent<Self::Address>, TransportError>> {
+        let second_first = self.second_first;
+        self.second_first = !second_first;
+        let first = self
+            .first
+            .recv()
+            .map(|event| event.map(|event| event.map(BothAddress::First)));
+        let second = self
+            .second
+            .recv()
+            .map(|event| event.map(|event| event.map(BothAddress::Second)));
+        async move {
+            if second_first {
+                future::select(second, first).await.factor_first().0
+            } else {
+                future::select(first, second).await.factor_first().0
+            }
+        }
+        .boxed()
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        to: Self::Address,
+        message: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), TransportError>> {
+        match to {
+            BothAddress::First(address) => self.first.send(address, message),
+            BothAddress::Second(address) => self.second.send(address, message),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use common::udp::{Datagram, MAX_DATAGRAM_LEN};
+use futures::{future::BoxFuture, FutureExt};
+use tokio::net::UdpSocket;
+
+use super::{Transport, TransportError, TransportEvent};
+
+// Peers that haven't sent anything for this long are dropped. Connected clients ping
+// every second, so this only catches ones that vanished without saying bye.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+// Every peer gets its own message processor and a hello is all it takes to become one,
+// from any address, spoofed or not. Hellos from new peers past this many are ignored.
+const MAX_PEERS: usize = 1024;
+
+// Plain UDP for native clients, framed as described in `common::udp`.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    buffer: Box<[u8]>,
+    // When each peer that completed the handshake was last heard from.
+    peers: HashMap<SocketAddr, Instant>,
+    pending: VecDeque<TransportEvent<SocketAddr>>,
+}
+
+impl UdpTransport {
+    // Binds through std and hands the socket to tokio, the same way webrtc_unreliable
+    // sets up its socket. tokio leaves making it non-blocking to us.
+    pub fn bind(address: SocketAddr) -> std::io::Result<Self> {
+        let socket = std::net::UdpSocket::bind(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket: UdpSocket::from_std(socket)?,
+            buffer: vec![0; MAX_DATAGRAM_LEN].into_boxed_slice(),
+            peers: HashMap::new(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn expire_peers(&mut self) {
+        let expired: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|(_, last_heard)| last_heard.elapsed() >= PEER_TIMEOUT)
+            .map(|(address, _)| *address)
+            .collect();
+        for address in expired {
+            tracing::info!("UDP peer {} timed out", address);
+            self.peers.remove(&address);
+            self.pending
+                .push_back(TransportEvent::Disconnected(address));
+        }
+    }
+
+    async fn send_datagram(
+        &self,
+        to: SocketAddr,
+        datagram: Datagram<'_>,
+    ) -> Result<(), TransportError> {
+        self.socket
+            .send_to(&datagram.encode(), to)
+            .await
+            .map(|_| ())
+            .map_err(|err| TransportError::Failed(err.to_string()))
+    }
+}
+
+impl Transport for UdpTransport {
+    type Address = SocketAddr;
+
+    fn recv(&mut self) -> BoxFuture<'_, Result<TransportEvent<SocketAddr>, TransportError>> {
+        async move {
+            loop {
+                self.expire_peers();
+                if let Some(event) = self.pending.pop_front() {
+                    return Ok(event);
+                }
+                let (len, from) = self
+                    .socket
+                    .recv_from(&mut self.buffer)
+                    .await
+                    .map_err(|err| TransportError::Failed(err.to_string()))?;
+                match Datagram::decode(&self.buffer[..len]) {
+                    // Welcomes get lost too, so every hello is answered.
+                    Some(Datagram::Hello) => {
+                        if !self.peers.contains_key(&from) && self.peers.len() >= MAX_PEERS {
+                            tracing::debug!("Too many UDP peers, ignoring hello from {}", from);
+                            continue;
+                        }
+                        self.send_datagram(from, Datagram::Welcome).await?;
+                        if self.peers.insert(from, Instant::now()).is_none() {
+                            return Ok(TransportEvent::Connected(from));
+                        }
+                    }
+                    Some(Datagram::Data(payload)) => {
+                        if let Some(last_heard) = self.peers.get_mut(&from) {
+                            *last_heard = Instant::now();
+                            return Ok(TransportEvent::Received(from, payload.into()));
+                        }
+                    }
+                    Some(Datagram::Bye) => {
+                        if self.peers.remove(&from).is_some() {
+                            return Ok(TransportEvent::Disconnected(from));
+                        }
+                    }
+                    Some(Datagram::Welcome) | None => {}
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        to: SocketAddr,
+        message: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), TransportError>> {
+        async move {
+            if !self.peers.contains_key(&to) {
+                return Err(TransportError::NotConnected);
+            }
+            self.send_datagram(to, Datagram::Data(message)).await
+        }
+        .boxed()
+    }
+}
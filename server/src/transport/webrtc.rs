@@ -0,0 +1,92 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    net::SocketAddr,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use webrtc_unreliable::{MessageType, Server as RtcServer, SessionEndpoint};
+
+use super::{Transport, TransportError, TransportEvent};
+
+// WebRTC data channels for browser clients. `webrtc_unreliable` doesn't report clients
+// coming and going, so they count as connected from their first message and as gone
+// once the server stops listing them.
+pub struct WebRtcTransport {
+    server: RtcServer,
+    known: HashSet<SocketAddr>,
+    pending: VecDeque<TransportEvent<SocketAddr>>,
+}
+
+impl WebRtcTransport {
+    pub fn new(server: RtcServer) -> Self {
+        Self {
+            server,
+            known: HashSet::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn session_endpoint(&self) -> SessionEndpoint {
+        self.server.session_endpoint()
+    }
+
+    fn check_disconnected(&mut self) {
+        let server = &self.server;
+        let gone: Vec<SocketAddr> = self
+            .known
+            .iter()
+            .filter(|address| !server.is_connected(address))
+            .copied()
+            .collect();
+        for address in gone {
+            self.known.remove(&address);
+            self.pending
+                .push_back(TransportEvent::Disconnected(address));
+        }
+    }
+}
+
+impl Transport for WebRtcTransport {
+    type Address = SocketAddr;
+
+    fn recv(&mut self) -> BoxFuture<'_, Result<TransportEvent<SocketAddr>, TransportError>> {
+        async move {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+            let (address, message) = {
+                let received = self
+                    .server
+                    .recv()
+                    .await
+                    .map_err(|err| TransportError::Failed(err.to_string()))?;
+                (received.remote_addr, received.message.as_ref().into())
+            };
+            if self.known.insert(address) {
+                self.pending
+                    .push_back(TransportEvent::Received(address, message));
+                return Ok(TransportEvent::Connected(address));
+            }
+            Ok(TransportEvent::Received(address, message))
+        }
+        .boxed()
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        to: SocketAddr,
+        message: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), TransportError>> {
+        async move {
+            self.check_disconnected();
+            if !self.server.is_connected(&to) {
+                return Err(TransportError::NotConnected);
+            }
+            self.server
+                .send(message, MessageType::Binary, &to)
+                .await
+                .map_err(|err| TransportError::Failed(err.to_string()))
+        }
+        .boxed()
+    }
+}
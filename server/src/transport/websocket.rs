@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use async_channel::{Receiver, Sender};
+use common::message::RawMessage;
+use futures::{future::BoxFuture, pin_mut, select, FutureExt, SinkExt, StreamExt};
+use hyper::upgrade::Upgraded;
+use sha1::{Digest, Sha1};
+use tokio_tungstenite::{
+    tungstenite::{protocol::Role, Message},
+    WebSocketStream,
+};
+
+use super::{Transport, TransportError, TransportEvent};
+
+// From RFC 6455, hashed together with the client's key to accept the upgrade.
+const WEBSOCKET_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+type Peers = Arc<Mutex<HashMap<SocketAddr, Sender<RawMessage>>>>;
+
+// WebSockets for browsers whose WebRTC data channel never opens. The lobby's hyper
+// server upgrades them and hands them over through a `WebSocketAcceptor`. Packets go
+// out as binary messages, TCP makes them reliable and ordered which turbulence
+// doesn't mind.
+pub struct WebSocketTransport {
+    events: Receiver<TransportEvent<SocketAddr>>,
+    acceptor: WebSocketAcceptor,
+}
+
+impl WebSocketTransport {
+    pub fn new() -> Self {
+        let (events, receiver) = async_channel::unbounded();
+        Self {
+            events: receiver,
+            acceptor: WebSocketAcceptor {
+                events,
+                peers: Peers::default(),
+            },
+        }
+    }
+
+    pub fn acceptor(&self) -> WebSocketAcceptor {
+        self.acceptor.clone()
+    }
+}
+
+// Feeds upgraded connections to a `WebSocketTransport`.
+#[derive(Clone)]
+pub struct WebSocketAcceptor {
+    events: Sender<TransportEvent<SocketAddr>>,
+    peers: Peers,
+}
+
+impl WebSocketAcceptor {
+    // Value of the `Sec-WebSocket-Accept` header answering a client's `Sec-WebSocket-Key`.
+    pub fn accept_key(key: &[u8]) -> String {
+        let mut sha1 = Sha1::default();
+        sha1.update(key);
+        sha1.update(WEBSOCKET_GUID);
+        base64::encode(sha1.finalize())
+    }
+
+    // Moves packets between the socket and the transport until either side closes it.
+    // The socket belongs to hyper's tokio, so this has to run there too.
+    pub async fn serve(self, address: SocketAddr, upgraded: Upgraded) {
+        let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+        let (mut sink, mut stream) = socket.split();
+        let (outgoing, outgoing_receiver) = async_channel::unbounded::<RawMessage>();
+        self.peers.lock().unwrap().insert(address, outgoing);
+        self.publish(TransportEvent::Connected(address));
+
+        let events = self.events.clone();
+        let receive = async move {
+            while let Some(message) = stream.next().await {
+                match message {
+                    Ok(Message::Binary(data)) => {
+                        let event = TransportEvent::Received(address, data.into_boxed_slice());
+                        if events.send(event).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(Message::Close(_)) => return,
+                    Ok(_) => {}
+                    Err(err) => {
+                        tracing::info!("WebSocket {} failed: {}", address, err);
+                        return;
+                    }
+                }
+            }
+        }
+        .fuse();
+        let dispatch = async move {
+            while let Ok(packet) = outgoing_receiver.recv().await {
+                if let Err(err) = sink.send(Message::Binary(packet.into_vec())).await {
+                    tracing::info!("Failed to send to WebSocket {}: {}", address, err);
+                    return;
+                }
+            }
+            let _ = sink.close().await;
+        }
+        .fuse();
+        pin_mut!(receive, dispatch);
+        select! {
+            () = receive => {},
+            () = dispatch => {},
+        };
+
+        self.peers.lock().unwrap().remove(&address);
+        self.publish(TransportEvent::Disconnected(address));
+    }
+
+    fn publish(&self, event: TransportEvent<SocketAddr>) {
+        if self.events.try_send(event).is_err() {
+            tracing::warn!("WebSocket transport is gone, dropping event");
+        }
+    }
+}
+
+impl Transport for WebSocketTransport {
+    type Address = SocketAddr;
+
+    fn recv(&mut self) -> BoxFuture<'_, Result<TransportEvent<SocketAddr>, TransportError>> {
+        async move {
+            self.events
+                .recv()
+                .await
+                .map_err(|err| TransportError::Failed(err.to_string()))
+        }
+        .boxed()
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        to: SocketAddr,
+        message: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), TransportError>> {
+        let sent = match self.acceptor.peers.lock().unwrap().get(&to) {
+            Some(peer) => peer
+                .try_send(message.into())
+                .map_err(|_| TransportError::NotConnected),
+            None => Err(TransportError::NotConnected),
+        };
+        futures::future::ready(sent).boxed()
+    }
+}
@@ -12,9 +12,43 @@ use web_sys::{
 };
 use wasm_bindgen::__rt::core::future::Future;
 use async_channel::SendError;
+use std::time::Duration;
 
 enum DispatcherEvent {
     ChannelOpen,
+    // The server answered the session request with this status and reason.
+    Rejected(u16, String),
+}
+
+// Why `WebRTCClient::open` gave up.
+#[derive(Debug)]
+pub enum OpenError {
+    TimedOut,
+    // The server refused the session with this status and reason.
+    Rejected(u16, String),
+}
+
+impl OpenError {
+    // Whether the session could still work over a WebSocket: WebRTC didn't get through,
+    // failed to negotiate (400) or is turned off (501). Anything else is the lobby turning
+    // us away, and it puts WebSockets through the same room checks.
+    pub fn can_fall_back(&self) -> bool {
+        matches!(
+            self,
+            OpenError::TimedOut | OpenError::Rejected(400, _) | OpenError::Rejected(501, _)
+        )
+    }
+}
+
+impl std::fmt::Display for OpenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpenError::TimedOut => write!(f, "The data channel didn't open in time"),
+            OpenError::Rejected(status, reason) => {
+                write!(f, "The server refused the session ({}): {}", status, reason)
+            }
+        }
+    }
 }
 
 #[wasm_bindgen]
@@ -67,10 +101,23 @@ impl WebRTCClient {
 
     pub fn close(&self) {
         self.channel.close();
+        self.peer.close();
     }
 
+    // Negotiates the session and waits up to `timeout` for the data channel to open.
+    // Gives up right away if the server refuses the session.
+    pub async fn open(&self, timeout: Duration) -> Result<(), OpenError> {
+        let connect = self.connect().fuse();
+        let timeout = futures_timer::Delay::new(timeout).fuse();
+        pin_mut!(connect, timeout);
+        select! {
+            result = connect => result,
+            () = timeout => Err(OpenError::TimedOut),
+        }
+    }
+
+    // Moves messages between the data channel and the processor, once `open` succeeded.
     pub async fn run(&self) {
-        self.connect().await;
         let incoming_rx = self.rtc_rx_incoming.clone();
         let processor_incoming_tx = self.processor_tx_incoming.clone();
 
@@ -114,7 +161,7 @@ impl WebRTCClient {
         };
     }
 
-    async fn connect(&self) {
+    async fn connect(&self) -> Result<(), OpenError> {
         let (tx_internal, rx_internal): (
             async_channel::Sender<DispatcherEvent>,
             async_channel::Receiver<DispatcherEvent>,
@@ -137,6 +184,7 @@ impl WebRTCClient {
             .set_onmessage(Some(on_message_callback.as_ref().unchecked_ref()));
         on_message_callback.forget();
 
+        let tx_rejected = tx_internal.clone();
         let on_channel_open = Closure::wrap(Box::new(move |_: MessageEvent| {
             tracing::info!("Channel opened");
             let tx = tx_internal.clone();
@@ -182,7 +230,8 @@ impl WebRTCClient {
 
         let request = XmlHttpRequest::new().unwrap();
         let request_clone = request.clone();
-        request.set_response_type(XmlHttpRequestResponseType::Json);
+        // Refusals come back as plain text, so the answer is parsed by hand.
+        request.set_response_type(XmlHttpRequestResponseType::Text);
 
         request.open("POST", &self.address).unwrap();
         let peer_clone = self.peer.clone();
@@ -190,7 +239,8 @@ impl WebRTCClient {
             Closure::new(move || match request_clone.status() {
                 Ok(200) => {
                     tracing::info!("Successful session request!");
-                    let response = request_clone.response().unwrap();
+                    let text = request_clone.response_text().unwrap().unwrap_or_default();
+                    let response = js_sys::JSON::parse(&text).unwrap();
                     tracing::info!("{:?}", response);
                     let answer_sdp;
 
@@ -250,8 +300,14 @@ impl WebRTCClient {
                         .then(&description_closure);
                     description_closure.forget();
                 }
-                _ => {
-                    tracing::error!("Failed to request session");
+                status => {
+                    let status = status.unwrap_or(0);
+                    let reason = request_clone.response_text().ok().flatten().unwrap_or_default();
+                    tracing::error!("Failed to request session: {} {}", status, reason);
+                    let tx = tx_rejected.clone();
+                    spawn_local(async move {
+                        let _ = tx.send(DispatcherEvent::Rejected(status, reason)).await;
+                    });
                 }
             });
         request.set_onload(Some(on_request_response_callback.as_ref().unchecked_ref()));
@@ -263,8 +319,9 @@ impl WebRTCClient {
         let my_listener = rx_internal;
 
         match my_listener.recv().await {
-            Ok(_) => (),
+            Ok(DispatcherEvent::ChannelOpen) => Ok(()),
+            Ok(DispatcherEvent::Rejected(status, reason)) => Err(OpenError::Rejected(status, reason)),
             _ => panic!("Failed"),
-        };
+        }
     }
 }
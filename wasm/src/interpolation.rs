@@ -0,0 +1,180 @@
+use std::collections::VecDeque;
+
+use common::snapshot::WorldState;
+
+// Keeps the last few world states received from the server, stamped with server time,
+// and blends between them so remote objects move smoothly instead of jumping from
+// one snapshot to the next.
+pub struct InterpolationBuffer {
+    // How far behind the estimated server time rendering happens. Needs to cover at
+    // least a couple of snapshot intervals so there's usually one on either side.
+    delay: f64,
+    // How far past the newest snapshot objects keep moving along their last velocity
+    // before they stop and wait for the next one.
+    max_extrapolation: f64,
+    snapshots: VecDeque<(f64, WorldState)>,
+}
+
+const BUFFER_LEN: usize = 32;
+
+impl InterpolationBuffer {
+    pub fn new(delay: f64, max_extrapolation: f64) -> Self {
+        Self {
+            delay,
+            max_extrapolation,
+            snapshots: VecDeque::with_capacity(BUFFER_LEN),
+        }
+    }
+
+    pub fn set_delay(&mut self, delay: f64) {
+        self.delay = delay;
+    }
+
+    pub fn set_max_extrapolation(&mut self, max_extrapolation: f64) {
+        self.max_extrapolation = max_extrapolation;
+    }
+
+    // Time to render at given the current estimated server time.
+    pub fn render_time(&self, server_time: f64) -> f64 {
+        server_time - self.delay
+    }
+
+    pub fn push(&mut self, time: f64, state: WorldState) {
+        if let Some((latest, _)) = self.snapshots.back() {
+            if time <= *latest {
+                return;
+            }
+        }
+        if self.snapshots.len() == BUFFER_LEN {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back((time, state));
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    // The world as it was at `render_time`. Objects only present in the later of the
+    // two surrounding snapshots show up where they were first seen.
+    pub fn sample(&self, render_time: f64) -> WorldState {
+        let (from, to) = match self.surrounding(render_time) {
+            Some(pair) => pair,
+            None => {
+                return self
+                    .snapshots
+                    .front()
+                    .map(|(_, state)| state.clone())
+                    .unwrap_or_default()
+            }
+        };
+        let (from_time, from_state) = from;
+        let (to_time, to_state) = to;
+        let span = to_time - from_time;
+        // Past the newest snapshot `render_time` is clamped so extrapolation is bounded.
+        let time = render_time.min(to_time + self.max_extrapolation);
+        let t = if span > 0.0 {
+            ((time - from_time) / span) as f32
+        } else {
+            1.0
+        };
+
+        let mut state = to_state.clone();
+        for (id, object) in state.iter_mut() {
+            let previous = from_state.get(id).and_then(|previous| previous.position());
+            if let (Some((x0, y0)), Some((x1, y1))) = (previous, object.position()) {
+                object.set_position((x0 + (x1 - x0) * t, y0 + (y1 - y0) * t));
+            }
+        }
+        state
+    }
+
+    // The snapshots on either side of `render_time`, or the last two when it's past
+    // the newest one.
+    fn surrounding(&self, render_time: f64) -> Option<(&(f64, WorldState), &(f64, WorldState))> {
+        if self.snapshots.len() < 2 {
+            return None;
+        }
+        let first = self.snapshots.front()?;
+        if render_time <= first.0 {
+            return None;
+        }
+        let index = self
+            .snapshots
+            .iter()
+            .position(|(time, _)| *time >= render_time)
+            .unwrap_or(self.snapshots.len() - 1)
+            .max(1);
+        Some((&self.snapshots[index - 1], &self.snapshots[index]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::message::Object;
+
+    fn world(objects: &[(u32, (f32, f32))]) -> WorldState {
+        objects
+            .iter()
+            .map(|(id, position)| {
+                let mut object = Object::new(*id);
+                object.set_position(*position);
+                (*id, object)
+            })
+            .collect()
+    }
+
+    fn position(state: &WorldState, id: u32) -> Option<(f32, f32)> {
+        state.get(&id).and_then(Object::position)
+    }
+
+    fn buffer() -> InterpolationBuffer {
+        let mut buffer = InterpolationBuffer::new(100.0, 50.0);
+        buffer.push(0.0, world(&[(1, (0.0, 0.0))]));
+        buffer.push(100.0, world(&[(1, (10.0, 0.0)), (2, (5.0, 5.0))]));
+        buffer.push(200.0, world(&[(1, (10.0, 20.0)), (2, (5.0, 5.0))]));
+        buffer
+    }
+
+    #[test]
+    fn blends_between_surrounding_snapshots() {
+        let buffer = buffer();
+        assert_eq!(position(&buffer.sample(50.0), 1), Some((5.0, 0.0)));
+        assert_eq!(position(&buffer.sample(150.0), 1), Some((10.0, 10.0)));
+        assert_eq!(position(&buffer.sample(100.0), 1), Some((10.0, 0.0)));
+    }
+
+    #[test]
+    fn new_objects_appear_where_first_seen() {
+        let buffer = buffer();
+        assert_eq!(position(&buffer.sample(50.0), 2), Some((5.0, 5.0)));
+    }
+
+    #[test]
+    fn extrapolation_stops_at_max_extrapolation() {
+        let buffer = buffer();
+        assert_eq!(position(&buffer.sample(225.0), 1), Some((10.0, 25.0)));
+        // Only 50ms past the newest snapshot, however late it gets.
+        assert_eq!(position(&buffer.sample(250.0), 1), Some((10.0, 30.0)));
+        assert_eq!(position(&buffer.sample(1000.0), 1), Some((10.0, 30.0)));
+    }
+
+    #[test]
+    fn holds_the_oldest_snapshot_until_it_can_blend() {
+        let buffer = buffer();
+        assert_eq!(position(&buffer.sample(-20.0), 1), Some((0.0, 0.0)));
+
+        let mut single = InterpolationBuffer::new(100.0, 50.0);
+        single.push(0.0, world(&[(1, (3.0, 4.0))]));
+        assert_eq!(position(&single.sample(500.0), 1), Some((3.0, 4.0)));
+        assert!(InterpolationBuffer::new(100.0, 50.0).sample(0.0).is_empty());
+    }
+
+    #[test]
+    fn ignores_snapshots_older_than_the_newest() {
+        let mut buffer = buffer();
+        buffer.push(150.0, world(&[(1, (-100.0, -100.0))]));
+        assert_eq!(position(&buffer.sample(150.0), 1), Some((10.0, 10.0)));
+    }
+}
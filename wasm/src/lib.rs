@@ -1,8 +1,11 @@
 #![feature(async_closure)]
 mod client;
+mod interpolation;
+mod offline;
 mod processor;
 mod runtime;
 mod utils;
+mod websocket;
 
 use wasm_bindgen::prelude::*;
 #[wasm_bindgen]
@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use async_channel::{Receiver, Sender};
+use common::host::GameHost;
+use common::message::{GameProtocol, InternalMessage, RawMessage, SignedMessage, Target};
+use common::multiplexer::ConnectionMultiplexer;
+use common::runtime::Runtime;
+use common::simulation::Simulation;
+use futures::{pin_mut, select, FutureExt};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use crate::runtime::WasmRuntime;
+
+const TICK: Duration = Duration::from_millis(1000 / 30);
+const TREE_COUNT: usize = 20;
+
+enum Action {
+    FromClient(RawMessage),
+    FromHost(SignedMessage<RawMessage>),
+    Tick,
+    Shutdown,
+}
+
+// Runs the authoritative world in the browser, standing in for the WebRTC server. The
+// host gets its own multiplexer and packets are handed between it and the client's
+// multiplexer through channels, so the client can't tell the difference.
+pub fn spawn_local_server(
+    client_packets: Receiver<SignedMessage<RawMessage>>,
+    client_raw: Sender<RawMessage>,
+    shutdown: Receiver<InternalMessage>,
+) {
+    let runtime = WasmRuntime::new();
+    let (host_packet_sender, host_packets) = async_channel::unbounded();
+    let mut multiplexer = ConnectionMultiplexer::<WasmRuntime, GameProtocol>::new(
+        runtime.clone(),
+        host_packet_sender,
+    );
+    let events = multiplexer.subscribe();
+    let messages = multiplexer.message_receiver();
+    let id = multiplexer.register();
+    let host_raw = multiplexer
+        .get_raw_channel(id)
+        .expect("connection was just registered");
+
+    let mut rng = SmallRng::seed_from_u64((js_sys::Math::random() * u32::MAX as f64) as u64);
+    let mut simulation = Simulation::new(TICK.as_secs_f32() * 1000.0);
+    simulation.spawn_trees(TREE_COUNT, &mut rng);
+    let mut host = GameHost::new(simulation, rng.gen());
+
+    let inner_runtime = runtime.clone();
+    runtime.spawn(async move {
+        let runtime = inner_runtime;
+        let mut next_tick = instant::Instant::now() + TICK;
+        loop {
+            let action = {
+                let from_client = client_packets.recv().fuse();
+                let from_host = host_packets.recv().fuse();
+                let tick = runtime
+                    .sleep(next_tick.saturating_duration_since(instant::Instant::now()))
+                    .fuse();
+                let stop = shutdown.recv().fuse();
+                pin_mut!(from_client, from_host, tick, stop);
+                select! {
+                    packet = from_client => match packet {
+                        Ok(packet) => Action::FromClient(packet.message),
+                        Err(_) => Action::Shutdown,
+                    },
+                    packet = from_host => match packet {
+                        Ok(packet) => Action::FromHost(packet),
+                        Err(_) => Action::Shutdown,
+                    },
+                    () = tick => Action::Tick,
+                    _ = stop => Action::Shutdown,
+                }
+            };
+            match action {
+                Action::FromClient(packet) => {
+                    if host_raw.send(packet).await.is_err() {
+                        tracing::info!("Local server connection closed, dropping packet");
+                    }
+                }
+                Action::FromHost(packet) => {
+                    if client_raw.send(packet.message).await.is_err() {
+                        tracing::info!("Client connection closed, dropping packet");
+                    }
+                }
+                Action::Tick => {
+                    next_tick += TICK;
+                    while let Ok(event) = events.try_recv() {
+                        host.handle_event(event);
+                    }
+                    while let Ok(message) = messages.try_recv() {
+                        host.handle_message(message);
+                    }
+                    host.tick();
+                    for message in host.outgoing() {
+                        if let Err(err) =
+                            multiplexer.send_message(Target::Client(message.id), message.message)
+                        {
+                            tracing::info!("Local server failed to send message: {}", err);
+                        }
+                    }
+                }
+                Action::Shutdown => {
+                    tracing::info!("Shutting down local server");
+                    let _ = multiplexer.kill(id);
+                    break;
+                }
+            }
+        }
+    });
+}
@@ -1,15 +1,19 @@
-use std::collections::HashMap;
 use futures::try_join;
 use futures::pin_mut;
 use futures::FutureExt;
-use crate::{client::WebRTCClient, runtime::WasmRuntime};
-use common::message::{Message, Object, ReliableMessage, SignedMessage, InternalMessage, RawMessage};
+use crate::{client::{OpenError, WebRTCClient}, interpolation::InterpolationBuffer, offline, runtime::WasmRuntime};
+use crate::websocket::{websocket_url, WebSocketClient};
+use common::message::{GameMessage, GameProtocol, Message, SignedMessage, InternalMessage, RawMessage};
 use futures::select;
-use js_sys::{Array, Promise};
+use js_sys::{Array, Promise, Reflect};
 use wasm_bindgen::{prelude::*, JsCast};
 use wasm_bindgen_futures::future_to_promise;
 use common::runtime::Runtime;
 use wasm_bindgen::__rt::core::time::Duration;
+use common::event::{ConnectionEvent, DisconnectReason};
+use common::session::{ClientSession, SessionEvent};
+use common::snapshot::WorldState;
+use common::component::{ComponentRegistry, PlayerInfo, Renderable, Transform};
 use common::multiplexer::ConnectionMultiplexer;
 
 #[wasm_bindgen]
@@ -18,10 +22,17 @@ extern "C" {
     pub type JSRustVec;
 }
 
+// Both in milliseconds, see `InterpolationBuffer`.
+const DEFAULT_INTERPOLATION_DELAY: f64 = 100.0;
+const DEFAULT_MAX_EXTRAPOLATION: f64 = 50.0;
+
+// How long the data channel gets to open before we retry the session over a WebSocket.
+// A server that refuses the session outright isn't waited on.
+const RTC_OPEN_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[wasm_bindgen]
 pub struct ConnectionBundle {
-    tx: async_channel::Sender<Message>,
-    reliable_tx: async_channel::Sender<ReliableMessage>,
+    tx: async_channel::Sender<GameMessage>,
     internal_tx: async_channel::Sender<InternalMessage>,
 
     channel_number: usize
@@ -30,20 +41,36 @@ pub struct ConnectionBundle {
 #[wasm_bindgen]
 pub struct Processor {
     connection_bundle: Option<ConnectionBundle>,
-    multiplexer: ConnectionMultiplexer<WasmRuntime>,
+    multiplexer: ConnectionMultiplexer<WasmRuntime, GameProtocol>,
     pending_messages: Vec<String>,
+    // Messages for JS from connection attempts running on their own, like the server
+    // refusing a session.
+    notice_sender: async_channel::Sender<String>,
+    notice_receiver: async_channel::Receiver<String>,
 
-    message_receiver: async_channel::Receiver<SignedMessage<Message>>,
-    reliable_message_receiver: async_channel::Receiver<SignedMessage<ReliableMessage>>,
+    message_receiver: async_channel::Receiver<SignedMessage<GameMessage>>,
+    connection_events: async_channel::Receiver<ConnectionEvent>,
     signed_packet_receiver: async_channel::Receiver<SignedMessage<RawMessage>>,
 
-    position: (f32, f32),
+    // Decodes components for JS.
+    components: ComponentRegistry<JsValue>,
 
-    state: HashMap<u32, Object>,
+    connected: bool,
 
-    players: HashMap<u32, (f32, f32)>,
+    session: ClientSession,
+    interpolation: InterpolationBuffer,
+}
 
-    connected: bool,
+// Components JS gets to see. Anything not listed here is left out of `state()`.
+fn component_registry() -> ComponentRegistry<JsValue> {
+    fn to_js<C: serde::Serialize>(component: C) -> Option<JsValue> {
+        serde_wasm_bindgen::to_value(&component).ok()
+    }
+    let mut registry = ComponentRegistry::new();
+    registry.register(to_js::<Transform>);
+    registry.register(to_js::<Renderable>);
+    registry.register(to_js::<PlayerInfo>);
+    registry
 }
 
 #[wasm_bindgen]
@@ -52,51 +79,136 @@ impl Processor {
         let (signed_packet_sender, signed_packet_receiver) = async_channel::unbounded();
         let mut multiplexer = common::multiplexer::ConnectionMultiplexer::new(WasmRuntime::new(), signed_packet_sender);
         let message_receiver = multiplexer.message_receiver();
-        let reliable_message_receiver = multiplexer.reliable_message_receiver();
-        let mut s = Self {
+        let connection_events = multiplexer.subscribe();
+        let (notice_sender, notice_receiver) = async_channel::unbounded();
+        Self {
             connection_bundle: None,
             multiplexer,
             connected: false,
             pending_messages: Vec::with_capacity(100),
-            position: (50.0, 50.0),
-            players: HashMap::new(),
-            state: HashMap::new(),
+            notice_sender,
+            notice_receiver,
+            components: component_registry(),
             message_receiver,
-            reliable_message_receiver,
-            signed_packet_receiver
-        };
-        s
+            connection_events,
+            signed_packet_receiver,
+            session: ClientSession::new(),
+            interpolation: InterpolationBuffer::new(DEFAULT_INTERPOLATION_DELAY, DEFAULT_MAX_EXTRAPOLATION),
+        }
     }
 
+    // Connects and lets the server pick a room.
     pub fn connect(&mut self, url: String) {
+        self.connect_webrtc(url, None);
+    }
+
+    // Connects straight into the room with `room` as its code, as listed by the lobby.
+    pub fn connect_to_room(&mut self, url: String, room: String) {
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}room={}", url, separator, room);
+        self.connect_webrtc(url, Some(room));
+    }
+
+    fn connect_webrtc(&mut self, url: String, room: Option<String>) {
         if self.connected {
             tracing::info!("Already connected please disconnect first");
             return
         }
-        let (tx, rx) = async_channel::unbounded();
-        let (reliable_tx, reliable_rx) = async_channel::unbounded();
-        let (internal_tx, internal_rx) = async_channel::unbounded();
-        let channel_number = self.multiplexer.register();
+        let (channel_number, internal_rx) = self.open_connection();
+        self.request_room(room);
+        let packets = self.signed_packet_receiver.clone();
+        let raw_channel = self.multiplexer.get_raw_channel(channel_number).expect("connection was just registered");
         let client = WebRTCClient::new(
-            url,
-            self.signed_packet_receiver.clone(),
-            self.multiplexer.get_raw_channel(channel_number),
+            url.clone(),
+            packets.clone(),
+            raw_channel.clone(),
             internal_rx.clone(),
         );
+        let inner_internal_rx = internal_rx.clone();
+        let notices = self.notice_sender.clone();
+        let promise = future_to_promise(async move {
+            let terminate = async move {
+                inner_internal_rx.recv().await;
+                tracing::info!("terminating connection2");
+            }.fuse();
+            let client = &client;
+            let client_runner = async move {
+                let err = match client.open(RTC_OPEN_TIMEOUT).await {
+                    Ok(()) => {
+                        client.run().await;
+                        return;
+                    }
+                    Err(err) => err,
+                };
+                client.close();
+                if !err.can_fall_back() {
+                    tracing::info!("{}", err);
+                    if let OpenError::Rejected(_, reason) = &err {
+                        let _ = notices.try_send(format!("Can't connect: {}", reason));
+                    }
+                    return;
+                }
+                let url = websocket_url(&url);
+                tracing::info!("{}, falling back to {}", err, url);
+                match WebSocketClient::new(&url, packets, raw_channel) {
+                    Ok(socket) => socket.run().await,
+                    Err(err) => tracing::info!("Failed to open WebSocket: {:?}", err),
+                }
+            }.fuse();
+            pin_mut!(client_runner, terminate);
+            select! {
+              () = client_runner => {},
+              () = terminate => {},
+            };
+            client.close();
+            Ok(JsValue::UNDEFINED)
+        });
+    }
+
+    // Plays against a world simulated in the browser instead of a server. Everything
+    // else about the processor works the same as after `connect`.
+    pub fn connect_offline(&mut self) {
+        if self.connected {
+            tracing::info!("Already connected please disconnect first");
+            return
+        }
+        let (channel_number, internal_rx) = self.open_connection();
+        offline::spawn_local_server(
+            self.signed_packet_receiver.clone(),
+            self.multiplexer.get_raw_channel(channel_number).expect("connection was just registered"),
+            internal_rx,
+        );
+    }
+
+    // Forgets everything about the world we were in. A new server session or room has
+    // its own clock, ticks and objects.
+    fn reset_world(&mut self) {
+        self.session.reset();
+        self.interpolation.clear();
+    }
 
+    // Registers a connection with the multiplexer and starts feeding it queued
+    // messages. Returns the connection's id and the receiver its shutdown is signalled on.
+    fn open_connection(&mut self) -> (usize, async_channel::Receiver<InternalMessage>) {
+        let (tx, rx) = async_channel::unbounded();
+        let (internal_tx, internal_rx) = async_channel::unbounded();
+        let channel_number = self.multiplexer.register();
+        self.reset_world();
+        // Anything queued while we weren't connected was meant for an older session.
+        self.session.outgoing();
 
         let queued_messages = rx;
-        let message_sender = self.multiplexer.get_message_channel(channel_number);
-        let reliable_message_sender = self.multiplexer.get_reliable_message_channel(channel_number);
+        let message_sender = self.multiplexer.get_message_channel(channel_number).expect("connection was just registered");
         let runtime = WasmRuntime::new();
         let inner = tx.clone();
+        let clock = self.session.clock();
         let inner_internal_rx = internal_rx.clone();
         runtime.spawn(async move {
             let runtime = WasmRuntime::new();
             let ping = async move {
                 loop {
                     tracing::info!("Ping");
-                    inner.try_send(Message::Sync).unwrap();
+                    inner.try_send(Message::Sync(clock.now()).into()).unwrap();
                     runtime.sleep(Duration::from_secs(1)).await;
                 }
             }.fuse();
@@ -104,15 +216,9 @@ impl Processor {
                 loop {
                     for message in queued_messages.recv().await {
                         tracing::info!("Processor sending message {:?}", message);
-                        message_sender.send(message).await.unwrap();
-                    }
-                }
-            }.fuse();
-            let reliable_dispatcher = async move {
-                loop {
-                    for message in reliable_rx.recv().await {
-                        tracing::info!("Processor sending reliable message {:?}", message);
-                        reliable_message_sender.send(message).await.unwrap();
+                        if message_sender.send(message).await.is_err() {
+                            tracing::info!("Connection closed, dropping message");
+                        }
                     }
                 }
             }.fuse();
@@ -120,47 +226,34 @@ impl Processor {
                 inner_internal_rx.recv().await;
                 tracing::info!("terminating connection1");
             }.fuse();
-            pin_mut!(dispatcher, reliable_dispatcher, ping, terminate);
+            pin_mut!(dispatcher, ping, terminate);
             select! {
                 () = dispatcher => {},
-                () = reliable_dispatcher => {},
                 () = ping => {},
                 () = terminate => {}
             };
         });
-        let inner_internal_rx = internal_rx.clone();
-        let promise = future_to_promise(async move {
-            let terminate = async move {
-                inner_internal_rx.recv().await;
-                tracing::info!("terminating connection2");
-            }.fuse();
-            let client_runner = client.run().fuse();
-            pin_mut!(client_runner, terminate);
-            select! {
-              () = client_runner => {},
-              () = terminate => {},
-            };
-            client.close();
-            Ok(JsValue::UNDEFINED)
-        });
         self.connection_bundle = Some(
             ConnectionBundle {
                 tx,
-                reliable_tx,
                 internal_tx,
                 channel_number
             }
         );
         self.connected = true;
+        (channel_number, internal_rx)
     }
 
     pub fn disconnect(&mut self) {
         let runtime = WasmRuntime::new();
-        let bundle = self.connection_bundle.as_ref();
+        // Taking the bundle stops `update` from queueing input for a dead connection.
+        let bundle = self.connection_bundle.take();
         match bundle {
             None => {}
             Some(bundle) => {
-                self.multiplexer.kill(bundle.channel_number);
+                if let Err(err) = self.multiplexer.kill(bundle.channel_number) {
+                    tracing::info!("Failed to kill connection: {}", err);
+                }
                 for _ in 0..100 {
                     match bundle.internal_tx.try_send(InternalMessage::Shutdown) {
                         Err(_) => {break;}
@@ -171,61 +264,65 @@ impl Processor {
             }
         }
         self.connected = false;
+        self.session.disconnected();
     }
 
     pub fn process_pending(&mut self) {
-        for _ in 0..10 {
-            match self.message_receiver.try_recv() {
-                Ok(message) => match message.message {
-                    Message::Sync => {}
-                    Message::Position(x, y) => {
-                        self.position = (x, y);
-                        self.pending_messages.push(format!("{:?}", self.position));
-                    }
-                    Message::Unknown => {}
-                    Message::State(object) => {
-                        self.state.insert(object.id, object);
-                    }
-                    Message::Player(_, _) => {}
-                },
-                Err(_) => {
-                    break;
+        while let Ok(event) = self.connection_events.try_recv() {
+            let current = self.connection_bundle.as_ref().map(|bundle| bundle.channel_number);
+            if current != Some(event.connection()) {
+                continue;
+            }
+            match event {
+                ConnectionEvent::Disconnected(_, DisconnectReason::Killed) => {}
+                ConnectionEvent::Disconnected(_, _) | ConnectionEvent::ProcessorCrashed(_, _) => {
+                    tracing::info!("Lost connection to server: {:?}", event);
+                    self.disconnect();
                 }
+                _ => {}
             }
         }
-        for _ in 0..10 {
-            match self.reliable_message_receiver.try_recv() {
-                Ok(message) => match message.message {
-                    ReliableMessage::State(object) => {
-                        tracing::info!("Getting state: {:?}", object);
-                        for (key, value) in object {
-                            self.state.insert(key, value);
-                        }
-                       // self.state = object;
-                    }
-                    ReliableMessage::Disconnected(client) => {
-                        tracing::info!("Disconnected: {:?}", client);
-                    }
-                    ReliableMessage::Connected(client) => {
-                        tracing::info!("Connected: {:?}", client);
-                    }
-                    ReliableMessage::Connect => {
-                        tracing::info!("Connect");
-                        self.connected = true;
-                    }
-                    ReliableMessage::Text(txt) => {
-                        tracing::info!("Received message: {:?}", txt);
-                        self.pending_messages.push(txt);
-                    }
-                },
-                Err(_) => {
-                    break;
+        for _ in 0..20 {
+            let message = match self.message_receiver.try_recv() {
+                Ok(message) => message.message,
+                Err(_) => break,
+            };
+            match self.session.handle_message(message) {
+                Some(SessionEvent::Snapshot { time, .. }) => {
+                    self.interpolation.push(time, self.session.state().clone());
+                }
+                Some(SessionEvent::JoinedRoom(_)) => self.interpolation.clear(),
+                Some(SessionEvent::TickStats(_)) | None => {}
+            }
+        }
+        self.flush();
+    }
+
+    // Sends whatever the session queued up.
+    fn flush(&mut self) {
+        let outgoing = self.session.outgoing();
+        let bundle = match self.connection_bundle.as_ref() {
+            Some(bundle) => bundle,
+            None => {
+                if !outgoing.is_empty() {
+                    tracing::info!("Tried to send message but no connection exists!");
                 }
+                return;
+            }
+        };
+        for message in outgoing {
+            if bundle.tx.try_send(message).is_err() {
+                tracing::info!("Connection closed, dropping message");
             }
         }
     }
+
     pub fn get_pending(&mut self) -> JSRustVec {
         self.process_pending();
+        self.pending_messages.extend(self.session.take_messages());
+        while let Ok(notice) = self.notice_receiver.try_recv() {
+            self.pending_messages.push(notice);
+        }
         if !self.pending_messages.is_empty() {
             tracing::info!("Pending: {:?}", self.pending_messages);
         }
@@ -239,38 +336,111 @@ impl Processor {
             self.send("Hello!".to_string());
         }
         self.process_pending();
-        serde_wasm_bindgen::to_value(&self.state).unwrap()
+        self.to_js(&self.session.predicted(self.session.state().clone()))
     }
 
-    pub fn click(&self, x: f32, y: f32) {
-        if !self.connected {
-            tracing::info!("Tried to send message but no connection exists!");
+    // Ids of the objects that were added, changed or removed since the last call, as
+    // `{ added, changed, removed }` arrays.
+    pub fn take_changes(&mut self) -> JsValue {
+        self.process_pending();
+        serde_wasm_bindgen::to_value(&self.session.take_changes()).unwrap()
+    }
+
+    // The server time remote objects should be drawn at right now, or undefined until
+    // the clocks are synced.
+    pub fn render_time(&self) -> Option<f64> {
+        self.server_time().map(|time| self.interpolation.render_time(time))
+    }
+
+    // Same shape as `state()`, but with positions blended for `render_time`.
+    pub fn interpolated_state(&mut self, render_time: f64) -> JsValue {
+        self.process_pending();
+        let state = self.interpolation.sample(render_time);
+        self.to_js(&self.session.predicted(state))
+    }
+
+    // Objects keyed by id, each as `{ id, components }` with every registered component
+    // decoded under its name.
+    fn to_js(&self, state: &WorldState) -> JsValue {
+        let objects = js_sys::Map::new();
+        for object in state.values() {
+            let components = js_sys::Object::new();
+            for (name, data) in &object.components {
+                if let Some(component) = self.components.convert(name, data) {
+                    Reflect::set(&components, &JsValue::from_str(name), &component).unwrap();
+                }
+            }
+            let entity = js_sys::Object::new();
+            Reflect::set(&entity, &"id".into(), &object.id.into()).unwrap();
+            Reflect::set(&entity, &"components".into(), &components).unwrap();
+            objects.set(&object.id.into(), &entity);
+        }
+        objects.into()
+    }
+
+    // Samples input for a frame that took `dt` milliseconds, applies it locally and
+    // sends it to the server. Should be called once per rendered frame.
+    pub fn update(&mut self, dt: f32) {
+        if self.connection_bundle.is_none() {
             return;
         }
-        match self.connection_bundle.as_ref() {
-            None => {
-                tracing::info!("Tried to send message but no connection exists!");
-            }
-            Some(bundle) => {
-                tracing::info!("Processor sending message position");
-                bundle.tx.try_send(Message::Position(x, y)).unwrap();
-            }
+        self.session.update(dt);
+        self.flush();
+    }
+
+    pub fn set_interpolation_delay(&mut self, delay: f64) {
+        self.interpolation.set_delay(delay);
+    }
+
+    pub fn set_max_extrapolation(&mut self, max_extrapolation: f64) {
+        self.interpolation.set_max_extrapolation(max_extrapolation);
+    }
+
+    // The server's clock in milliseconds, or undefined until the first sync finished.
+    pub fn server_time(&self) -> Option<f64> {
+        self.session.server_time()
+    }
+
+    // Network stats for the current connection, or null when there isn't one.
+    pub fn stats(&self) -> JsValue {
+        let stats = self
+            .connection_bundle
+            .as_ref()
+            .and_then(|bundle| self.multiplexer.stats(bundle.channel_number).ok());
+        match stats {
+            Some(stats) => serde_wasm_bindgen::to_value(&stats).unwrap(),
+            None => JsValue::NULL,
         }
     }
 
-    pub fn send(&self, string: String) {
+    pub fn click(&mut self, x: f32, y: f32) {
+        self.session.click(x, y);
+        self.flush();
+    }
+
+    // Code of the room we're in, once the server has put us in one.
+    pub fn room(&self) -> Option<String> {
+        self.session.room().map(str::to_string)
+    }
+
+    // Moves to another room on the same server.
+    pub fn join_room(&mut self, code: String) {
+        self.request_room(Some(code));
+    }
+
+    // The server holds off on putting us anywhere until we ask for a room.
+    fn request_room(&mut self, room: Option<String>) {
+        self.session.request_room(room);
+        self.flush();
+    }
+
+    pub fn send(&mut self, string: String) {
         if !self.connected {
             tracing::info!("Tried to send message but no connection exists!");
             return;
         }
-        match self.connection_bundle.as_ref() {
-            None => {
-                tracing::info!("Tried to send message but no connection exists!");
-            }
-            Some(bundle) => {
-                tracing::info!("Processor sending message {:?}", string);
-                bundle.reliable_tx.try_send(ReliableMessage::Text(string)).unwrap();
-            }
-        }
+        tracing::info!("Processor sending message {:?}", string);
+        self.session.send_text(string);
+        self.flush();
     }
 }
@@ -0,0 +1,115 @@
+use async_channel::{Receiver, Sender};
+use common::message::SignedMessage;
+use futures::{pin_mut, select, FutureExt};
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{BinaryType, MessageEvent, WebSocket};
+
+// Carries the same packets as `WebRTCClient` over a WebSocket, for networks where the
+// data channel never opens. Everything arrives in order and nothing is dropped, so
+// it is slower to recover from loss, but it gets through.
+pub struct WebSocketClient {
+    socket: WebSocket,
+    processor_rx_outgoing: Receiver<SignedMessage<Box<[u8]>>>,
+    processor_tx_incoming: Sender<Box<[u8]>>,
+    socket_rx_incoming: Receiver<Box<[u8]>>,
+    open_rx: Receiver<()>,
+}
+
+impl WebSocketClient {
+    pub fn new(
+        url: &str,
+        processor_reader: Receiver<SignedMessage<Box<[u8]>>>,
+        processor_sender: Sender<Box<[u8]>>,
+    ) -> Result<Self, JsValue> {
+        let socket = WebSocket::new(url)?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+
+        let (socket_tx_incoming, socket_rx_incoming) = async_channel::unbounded();
+        let (open_tx, open_rx) = async_channel::unbounded();
+
+        let incoming = socket_tx_incoming.clone();
+        let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+            let data = js_sys::Uint8Array::new(&event.data()).to_vec();
+            let tx = incoming.clone();
+            spawn_local(async move {
+                let _ = tx.send(data.into_boxed_slice()).await;
+            });
+        }) as Box<dyn FnMut(MessageEvent)>);
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        on_message.forget();
+
+        let on_open = Closure::wrap(Box::new(move |_: JsValue| {
+            tracing::info!("WebSocket opened");
+            let _ = open_tx.try_send(());
+        }) as Box<dyn FnMut(JsValue)>);
+        socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        on_open.forget();
+
+        // Closing the channel ends `run`, whether the socket closed or never opened.
+        let on_close = Closure::wrap(Box::new(move |_: JsValue| {
+            tracing::info!("WebSocket closed");
+            socket_tx_incoming.close();
+        }) as Box<dyn FnMut(JsValue)>);
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+        on_close.forget();
+
+        Ok(Self {
+            socket,
+            processor_rx_outgoing: processor_reader,
+            processor_tx_incoming: processor_sender,
+            socket_rx_incoming,
+            open_rx,
+        })
+    }
+
+    // Runs until the socket closes.
+    pub async fn run(&self) {
+        let opened = async {
+            let _ = self.open_rx.recv().await;
+        }
+        .fuse();
+        let closed = async { while self.socket_rx_incoming.recv().await.is_ok() {} }.fuse();
+        pin_mut!(opened, closed);
+        select! {
+            () = opened => {},
+            () = closed => return,
+        };
+
+        let incoming = async {
+            while let Ok(message) = self.socket_rx_incoming.recv().await {
+                if self.processor_tx_incoming.send(message).await.is_err() {
+                    tracing::info!("Failed to dispatch incoming message");
+                }
+            }
+        }
+        .fuse();
+        let outgoing = async {
+            while let Ok(message) = self.processor_rx_outgoing.recv().await {
+                if let Err(err) = self.socket.send_with_u8_array(message.message.as_ref()) {
+                    tracing::info!("Failed to send over WebSocket: {:?}", err);
+                }
+            }
+        }
+        .fuse();
+        pin_mut!(incoming, outgoing);
+        select! {
+            () = incoming => {},
+            () = outgoing => {},
+        };
+    }
+}
+
+impl Drop for WebSocketClient {
+    fn drop(&mut self) {
+        let _ = self.socket.close();
+    }
+}
+
+// The WebSocket address of a `/session` URL, same host and port, same query.
+pub fn websocket_url(session_url: &str) -> String {
+    match session_url.strip_prefix("http") {
+        Some(rest) => format!("ws{}", rest),
+        None => session_url.to_string(),
+    }
+}